@@ -0,0 +1,38 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.4 Bandwidth Request Event TRB
+/// Generated when a device on a slot asks for a different amount of periodic bandwidth (xHCI 4.16.1).
+/// Only xHCs that support the Negotiate Bandwidth Command generate this.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciBandwidthRequestEventTrb {
+    _reserved_0: u64,
+    pub status: BandwidthRequestEventStatus,
+    pub control: BandwidthRequestEventControl,
+}
+
+event_trb!(
+    XhciBandwidthRequestEventTrb,
+    XhciTrbType::BandwidthRequestEvent
+);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct BandwidthRequestEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct BandwidthRequestEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+    u8; pub slot_id, _: 31, 24;
+}
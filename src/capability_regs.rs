@@ -58,14 +58,15 @@ bitfield! {
 
     u8; pub isochronous_scheduling_threshold, _: 3, 0;
     u8; pub erst_max, _: 7, 4;
-    u8; max_scratchpad_buffers_hi, _: 25, 21;
+    u16; max_scratchpad_buffers_hi, _: 25, 21;
     bool; pub scratchpad_restore, _: 26;
-    u8; max_scratchpad_buffers_lo, _: 31, 27;
+    u16; max_scratchpad_buffers_lo, _: 31, 27;
 }
 
 impl HcsParams2 {
-    pub fn max_scratchpad_buffers(&self) -> u8 {
-        self.max_scratchpad_buffers_lo() | (self.max_scratchpad_buffers_hi() << 4)
+    /// The Hi field is the high order 5 bits and the Lo field is the low order 5 bits, so this can be up to 1023.
+    pub fn max_scratchpad_buffers(&self) -> u16 {
+        self.max_scratchpad_buffers_lo() | (self.max_scratchpad_buffers_hi() << 5)
     }
 }
 
@@ -105,6 +106,17 @@ bitfield! {
     pub xhci_extended_capabilities_ptr, _: 31, 16;
 }
 
+impl HccParams1 {
+    /// If 64-bit Addressing Capability (AC64) isn't set, the xHC ignores the high 32 bits of every pointer we give it
+    pub fn max_phys_addr(&self) -> u64 {
+        if self.ac64() {
+            u64::MAX
+        } else {
+            u32::MAX.into()
+        }
+    }
+}
+
 bitfield! {
     /// xHCI 5.3.9 Capability Parameters 2 (HCCPARAMS2)
     #[derive(Clone, Copy)]
@@ -0,0 +1,56 @@
+use core::time::Duration;
+
+/// Your kernel's way of telling time.
+/// Every time the driver waits for the xHC to change a register, it uses this to give up after a timeout instead of spinning forever.
+pub trait XhciClock {
+    /// A monotonic timestamp. It doesn't matter what it's relative to, but it must never go backwards.
+    fn now(&self) -> Duration;
+    /// Wait for at least `duration`. You can sleep, yield to other tasks, or just spin.
+    fn delay(&mut self, duration: Duration);
+}
+
+/// How long to wait between reading a register that we're polling
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_micros(10);
+
+/// xHCI 5.4.1 USB Command Register (USBCMD)
+/// > The xHC shall halt within 16 ms after software clears the Run/Stop bit if the above conditions have been met.
+pub const HALT_TIMEOUT: Duration = Duration::from_millis(16);
+/// The spec doesn't say how long this takes, but Linux gives up after 1 second, and it's even more generous for controllers with known quirks.
+pub const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+/// Same as [`RESET_TIMEOUT`], because CNR is cleared at the end of the reset process.
+pub const CONTROLLER_NOT_READY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Once Run/Stop is set, the xHC should clear HCHalted about as fast as it sets it.
+pub const START_TIMEOUT: Duration = HALT_TIMEOUT;
+/// USB 2.0 7.1.7.5 Reset Signaling says a reset from a root port lasts at least 50 ms. USB 3 warm resets can take longer, so we give it 10 times that.
+pub const PORT_RESET_TIMEOUT: Duration = Duration::from_millis(500);
+/// xHCI 4.22.1 doesn't say how long the BIOS can take to give up the xHC. Linux waits 1 second.
+pub const BIOS_HANDOFF_TIMEOUT: Duration = Duration::from_secs(1);
+/// xHCI 4.6.1.2 Aborting a Command doesn't give a time limit. Linux waits 5 seconds for CRR to clear.
+pub const COMMAND_ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// xHCI 4.23.2 Save and Restore State doesn't say how long saving takes. Linux waits 20 ms for SSS to clear.
+pub const SAVE_STATE_TIMEOUT: Duration = Duration::from_millis(20);
+/// Same as [`SAVE_STATE_TIMEOUT`], but for RSS.
+pub const RESTORE_STATE_TIMEOUT: Duration = SAVE_STATE_TIMEOUT;
+
+/// The register didn't change in time
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout;
+
+/// Keep checking `condition` until it is `true`, or return [`Timeout`] after `timeout` passes.
+pub(crate) fn poll_until(
+    clock: &mut impl XhciClock,
+    timeout: Duration,
+    mut condition: impl FnMut() -> bool,
+) -> Result<(), Timeout> {
+    let start = clock.now();
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if clock.now().saturating_sub(start) >= timeout {
+            // Check one last time, in case we got delayed for a long time right before the deadline
+            return if condition() { Ok(()) } else { Err(Timeout) };
+        }
+        clock.delay(POLL_INTERVAL);
+    }
+}
@@ -1,7 +1,7 @@
 use bitfield::bitfield;
 use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
 
-use crate::{trb::AnyTrb, trb_type::XhciTrbType};
+use crate::{CompletionCode, CompletionError, trb::AnyTrb, trb_type::XhciTrbType};
 
 /// xHCI 6.4.2.2 Command Completion Event TRB
 #[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
@@ -16,15 +16,15 @@ pub enum TrbConvertError {
     WrongType(u8),
 }
 
-impl TryFrom<AnyTrb> for XhciCommandCompletionEventTrb {
-    type Error = TrbConvertError;
-    fn try_from(value: AnyTrb) -> Result<Self, Self::Error> {
-        let trb_type = value.control.trb_type();
-        if trb_type == XhciTrbType::CmdCompletionEvent.into() {
-            Ok(transmute!(value))
-        } else {
-            Err(TrbConvertError::WrongType(trb_type))
-        }
+event_trb!(
+    XhciCommandCompletionEventTrb,
+    XhciTrbType::CmdCompletionEvent
+);
+
+impl XhciCommandCompletionEventTrb {
+    /// `Ok` if the command succeeded
+    pub fn result(&self) -> Result<(), CompletionError> {
+        self.status.completion_code().into_result()
     }
 }
 
@@ -54,7 +54,7 @@ bitfield! {
     /// This field encodes the completion status of the command that generated the event.
     /// Refer to the respective command definition for a list of the possible Completion Codes associated with the command.
     /// Refer to section 6.4.5 for an enumerated list of possible error conditions.
-    pub completion_code, _: 31, 24;
+    pub from into CompletionCode, completion_code, set_completion_code: 31, 24;
 }
 
 bitfield! {
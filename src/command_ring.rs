@@ -1,19 +1,22 @@
-use core::{
-    mem::MaybeUninit,
-    num::NonZero,
-    ptr::{NonNull, slice_from_raw_parts_mut},
+use alloc::{
+    collections::{VecDeque, btree_map::BTreeMap},
+    vec::Vec,
 };
+use core::num::NonZero;
 
 use volatile::VolatilePtr;
-use zerocopy::{transmute, transmute_ref};
+use zerocopy::{FromZeros, transmute};
 
 use crate::*;
 
 /// 4.9 TRB Ring
+/// The ring is made of segments that are chained together with Link TRBs (xHCI 4.9.2.1 Segmented Rings).
+/// Positions in the ring are indexes into all of the segments put together, so the last TRB of every segment is a Link TRB.
 #[derive(Debug)]
 pub struct CommandRing2<'a> {
-    ring_mem: AllocResponse,
-    ring: &'a mut [AnyTrb],
+    segments: Vec<CommandRingSegment<'a>>,
+    /// The number of TRBs in each segment, including the Link TRB
+    segment_len: usize,
     /// This is a position in the ring that we are at
     enqueue_pointer: usize,
     producer_cycle_state: bool,
@@ -23,87 +26,325 @@ pub struct CommandRing2<'a> {
     /// > The location of the Command Ring Dequeue Pointer is reported on the Event Ring in Command Completion Events.
     dequeue_pointer: usize,
     consumer_cycle_state: bool,
+    /// Commands that were enqueued, keyed by the physical address of their TRB.
+    /// The completion is filled in when the xHC's Command Completion Event arrives, and removed when it's taken.
+    completions: BTreeMap<u64, CommandCompletion>,
+    /// The command that we set Command Abort (CA) for, until the Command Ring Stopped event arrives
+    aborting: Option<u64>,
+    /// A command that was turned into a No Op because the xHC stopped before executing it.
+    /// Its completion is reported as Command Aborted.
+    aborted_as_no_op: Option<u64>,
+    /// Commands from [`Driver::submit_command_async`] that didn't fit on the ring, oldest first.
+    /// They are moved onto the ring when Command Completion Events free up TRBs.
+    overflow: VecDeque<(AnyTrb, Completer<XhciCommandCompletionEventTrb>)>,
+    /// The maximum length of `overflow`
+    overflow_capacity: usize,
+    /// Commands were put on the ring while handling events (after an abort stopped the ring, or from `overflow`),
+    /// so the command doorbell needs to be rung
+    doorbell_needed: bool,
+}
+
+#[derive(Debug)]
+struct CommandRingSegment<'a> {
+    mem: XhciAllocation,
+    trbs: &'a mut [AnyTrb],
+}
+
+/// Where the Command Completion Event of a command goes
+#[derive(Debug)]
+pub enum CommandCompletion {
+    /// Kept until it's taken with [`CommandRing2::take_completion`]
+    Stored(Option<XhciCommandCompletionEventTrb>),
+    /// Sent to a [`CommandFuture`]
+    Awaited(Completer<XhciCommandCompletionEventTrb>),
 }
 
 impl CommandRing2<'_> {
-    /// Also updates CRCR
-    pub fn new(len: usize, crcr: VolatilePtr<Crcr>, allocator: &mut impl XhciMemAllocator) -> Self {
-        let command_ring_len = len;
-        let command_ring_size = command_ring_len * size_of::<AnyTrb>();
-        let command_ring_mem = allocator.alloc(AllocRequest {
-            size: NonZero::new(command_ring_size as u64).unwrap(),
-            align: XHCI_COMMAND_RING_SEGMENTS_ALIGNMENT,
-            boundary: XHCI_COMMAND_RING_SEGMENTS_BOUNDARY,
-        });
-        let command_ring = {
-            {
-                let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                    command_ring_mem.virt_addr.get() as *mut MaybeUninit<AnyTrb>,
-                    command_ring_len,
-                ))
-                .unwrap();
-                let command_ring_uninit = unsafe { ptr.as_mut() };
-                // Initially when the TRB Ring is created in memory, or if it is ever re -initialized, all TRBs in the ring shall be cleared to ‘0’. This state represents an empty queue.
-                command_ring_uninit.fill(MaybeUninit::zeroed());
-            }
-            let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                command_ring_mem.virt_addr.get() as *mut AnyTrb,
-                command_ring_len,
-            ))
-            .unwrap();
-            unsafe { ptr.as_mut() }
+    /// Allocates `segments` segments of `segment_len` TRBs (including the Link TRB) each.
+    /// Up to `overflow_capacity` commands can wait in memory while the ring is full.
+    /// Use [`Self::update_crcr`] to give it to the xHC.
+    pub fn new(
+        segment_len: usize,
+        segments: usize,
+        overflow_capacity: usize,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, InitError> {
+        // We need at least 1 TRB for commands and 1 TRB for the Link TRB
+        if segment_len < 2 {
+            Err(UnsupportedConfiguration::RingTooSmall)?;
+        }
+        let mut command_ring = Self {
+            segments: Vec::with_capacity(segments),
+            segment_len,
+            enqueue_pointer: 0,
+            producer_cycle_state: true,
+            dequeue_pointer: 0,
+            consumer_cycle_state: true,
+            completions: BTreeMap::new(),
+            aborting: None,
+            aborted_as_no_op: None,
+            overflow: VecDeque::new(),
+            overflow_capacity,
+            doorbell_needed: false,
         };
+        for _ in 0..segments {
+            // Each segment has to be within a 64 KiB boundary, but the segments don't have to be next to each other
+            let segment_mem = match XhciAllocation::new(
+                allocator,
+                AllocRequest {
+                    size: NonZero::new((segment_len * size_of::<AnyTrb>()) as u64)
+                        .ok_or(UnsupportedConfiguration::RingTooSmall)?,
+                    align: XHCI_COMMAND_RING_SEGMENTS_ALIGNMENT,
+                    boundary: XHCI_COMMAND_RING_SEGMENTS_BOUNDARY,
+                    max_phys_addr,
+                },
+            ) {
+                Ok(segment_mem) => segment_mem,
+                Err(e) => {
+                    // The xHC doesn't know about any of the segments yet, so we can give them back
+                    unsafe { command_ring.free(allocator) };
+                    return Err(e.into());
+                }
+            };
+            let trbs = unsafe { segment_mem.response().as_zeroed_slice(segment_len) };
+            command_ring.segments.push(CommandRingSegment {
+                mem: segment_mem,
+                trbs,
+            });
+        }
+        if command_ring.segments.is_empty() {
+            Err(UnsupportedConfiguration::RingTooSmall)?;
+        }
+        command_ring.reinitialize();
+        Ok(command_ring)
+    }
+
+    /// Puts the ring back into the state it was in right after it was allocated.
+    /// Any commands that were on it are gone.
+    /// The xHC must not be using the ring, so it must be halted (or reset).
+    pub fn reinitialize(&mut self) {
         let initial_cycle_state = true;
-        // Make the last TRB a link TRB
-        *command_ring.last_mut().unwrap() = transmute!(LinkTrb::new(
-            command_ring_mem.phys_addr,
-            initial_cycle_state,
-            true
-        ));
+        let segments_len = self.segments.len();
+        for i in 0..segments_len {
+            let next_segment_phys_addr = self.segments[(i + 1) % segments_len].mem.phys_addr();
+            let trbs = &mut self.segments[i].trbs;
+            // Initially when the TRB Ring is created in memory, or if it is ever re -initialized, all TRBs in the ring shall be cleared to ‘0’. This state represents an empty queue.
+            trbs.zero();
+            // Make the last TRB of each segment a link TRB to the next segment.
+            // Only the one that goes from the last segment back to the first segment toggles the cycle state.
+            let link_trb_index = trbs.len() - 1;
+            trbs[link_trb_index] = transmute!(LinkTrb::new(
+                next_segment_phys_addr,
+                initial_cycle_state,
+                i == segments_len - 1
+            ));
+        }
+        self.enqueue_pointer = 0;
+        self.producer_cycle_state = initial_cycle_state;
+        self.dequeue_pointer = 0;
+        self.consumer_cycle_state = initial_cycle_state;
+        // These commands will never complete, so their futures are cancelled
+        self.completions.clear();
+        self.overflow.clear();
+        self.aborting = None;
+        self.aborted_as_no_op = None;
+        self.doorbell_needed = false;
+    }
 
+    /// Points CRCR to the next command that the xHC hasn't executed yet.
+    /// CRCR can only be written while the command ring isn't running, and reading the pointer back always gives 0,
+    /// so this is the only way to tell the xHC where to continue after it was reset or lost power.
+    pub fn update_crcr(&self, crcr: VolatilePtr<Crcr>) {
         crcr.update(|mut crcr| {
-            crcr.set_command_ring_ptr(command_ring_mem.phys_addr);
-            crcr.set_ring_cycle_state(initial_cycle_state);
+            crcr.set_command_ring_ptr(self.trb_phys_addr(self.dequeue_pointer));
+            crcr.set_ring_cycle_state(self.consumer_cycle_state);
             crcr
         });
+    }
 
-        Self {
-            ring_mem: command_ring_mem,
-            ring: command_ring,
-            enqueue_pointer: 0,
-            producer_cycle_state: initial_cycle_state,
-            dequeue_pointer: 0,
-            consumer_cycle_state: initial_cycle_state,
+    /// # Safety
+    /// The xHC must be halted, or CRCR must point to a different command ring.
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        for segment in self.segments {
+            unsafe { segment.mem.free(allocator) };
         }
     }
 
-    /// The cycle bit will be set by this function
-    pub fn try_enqueue(&mut self, mut trb: AnyTrb) -> Result<(), EnqueueError> {
-        let can_enqueue = if self.consumer_cycle_state == self.producer_cycle_state {
+    /// The number of TRBs in all of the segments, including the Link TRBs
+    fn len(&self) -> usize {
+        self.segments.len() * self.segment_len
+    }
+
+    fn is_link_trb(&self, index: usize) -> bool {
+        index % self.segment_len == self.segment_len - 1
+    }
+
+    fn trb_mut(&mut self, index: usize) -> &mut AnyTrb {
+        &mut self.segments[index / self.segment_len].trbs[index % self.segment_len]
+    }
+
+    fn trb_phys_addr(&self, index: usize) -> u64 {
+        self.segments[index / self.segment_len].mem.phys_addr()
+            + (index % self.segment_len) as u64 * size_of::<AnyTrb>() as u64
+    }
+
+    /// The position of the TRB at `trb_phys_addr`, if it is in one of the segments
+    fn trb_index(&self, trb_phys_addr: u64) -> Option<usize> {
+        self.segments
+            .iter()
+            .enumerate()
+            .find_map(|(segment_index, segment)| {
+                let offset = trb_phys_addr.checked_sub(segment.mem.phys_addr())? as usize
+                    / size_of::<AnyTrb>();
+                (offset < self.segment_len).then_some(segment_index * self.segment_len + offset)
+            })
+    }
+
+    fn is_full(&self) -> bool {
+        if self.consumer_cycle_state == self.producer_cycle_state {
+            self.enqueue_pointer < self.dequeue_pointer
+        } else {
             self.enqueue_pointer >= self.dequeue_pointer
+        }
+    }
+
+    /// The cycle bit will be set by this function.
+    /// Returns the physical address of the TRB, which is what the Command Completion Event will point to.
+    pub fn try_enqueue(
+        &mut self,
+        trb: AnyTrb,
+        completion: CommandCompletion,
+    ) -> Result<u64, EnqueueError> {
+        // Commands in the overflow queue were submitted first, so nothing can skip ahead of them
+        if !self.is_full() && self.overflow.is_empty() {
+            Ok(self.enqueue(trb, completion))
         } else {
-            self.enqueue_pointer < self.dequeue_pointer
-        };
-        if can_enqueue {
-            trb.control.set_cycle_bit(self.producer_cycle_state);
-            self.ring[self.enqueue_pointer] = trb;
+            Err(EnqueueError::IsFull)
+        }
+    }
+
+    /// Writes the TRB at the enqueue pointer. The ring must not be full.
+    fn enqueue(&mut self, mut trb: AnyTrb, completion: CommandCompletion) -> u64 {
+        trb.control.set_cycle_bit(self.producer_cycle_state);
+        *self.trb_mut(self.enqueue_pointer) = trb;
+        let trb_phys_addr = self.trb_phys_addr(self.enqueue_pointer);
+        if self
+            .completions
+            .insert(trb_phys_addr, completion)
+            .is_some_and(|completion| matches!(completion, CommandCompletion::Stored(Some(_))))
+        {
+            log::warn!(
+                "xHCI - Command completion for {trb_phys_addr:#X} was never taken, and the TRB is being reused"
+            );
+        }
 
+        self.enqueue_pointer += 1;
+        if self.is_link_trb(self.enqueue_pointer) {
+            // Give the Link TRB to the xHC by updating its cycle bit
+            let producer_cycle_state = self.producer_cycle_state;
+            self.trb_mut(self.enqueue_pointer)
+                .control
+                .set_cycle_bit(producer_cycle_state);
             self.enqueue_pointer += 1;
-            if self.enqueue_pointer == self.ring.len() - 1 {
-                // Update the producer cycle bit and also update the cycle bit in the Link TRB
-                self.ring[self.enqueue_pointer]
-                    .control
-                    .set_cycle_bit(self.producer_cycle_state);
+            // Only the Link TRB at the end of the last segment has Toggle Cycle set
+            if self.enqueue_pointer == self.len() {
                 self.producer_cycle_state = !self.producer_cycle_state;
                 self.enqueue_pointer = 0;
             }
+        }
 
-            Ok(())
+        trb_phys_addr
+    }
+
+    /// Like [`Self::try_enqueue`], but if the ring is full the command waits in the overflow queue instead.
+    /// Returns `true` if the command is on the ring, and `false` if it's in the overflow queue.
+    pub fn enqueue_or_queue(
+        &mut self,
+        trb: AnyTrb,
+        completer: Completer<XhciCommandCompletionEventTrb>,
+    ) -> Result<bool, EnqueueError> {
+        if !self.is_full() && self.overflow.is_empty() {
+            self.enqueue(trb, CommandCompletion::Awaited(completer));
+            Ok(true)
+        } else if self.overflow.len() < self.overflow_capacity {
+            self.overflow.push_back((trb, completer));
+            Ok(false)
         } else {
             Err(EnqueueError::IsFull)
         }
     }
 
+    /// Moves as many commands as will fit from the overflow queue onto the ring.
+    /// A TRB whose completion from [`Self::take_completion`] wasn't taken yet isn't reused, so the completion doesn't get lost.
+    fn drain_overflow(&mut self) {
+        while !self.is_full()
+            && !matches!(
+                self.completions
+                    .get(&self.trb_phys_addr(self.enqueue_pointer)),
+                Some(CommandCompletion::Stored(Some(_)))
+            )
+        {
+            let Some((trb, completer)) = self.overflow.pop_front() else {
+                break;
+            };
+            self.enqueue(trb, CommandCompletion::Awaited(completer));
+            self.doorbell_needed = true;
+        }
+    }
+
+    /// The position of the next command TRB that the xHC will execute, and the cycle state it will have there.
+    /// The dequeue pointer can be on a Link TRB (after the last command of a segment completed), but the xHC will follow it without executing anything.
+    fn next_command(&self) -> (usize, bool) {
+        if self.is_link_trb(self.dequeue_pointer) {
+            let index = self.dequeue_pointer + 1;
+            // Only the Link TRB at the end of the last segment toggles the cycle state
+            if index == self.len() {
+                (0, !self.consumer_cycle_state)
+            } else {
+                (index, self.consumer_cycle_state)
+            }
+        } else {
+            (self.dequeue_pointer, self.consumer_cycle_state)
+        }
+    }
+
+    /// Whether there are commands that the xHC didn't finish
+    fn has_pending_commands(&self) -> bool {
+        self.next_command() != (self.enqueue_pointer, self.producer_cycle_state)
+    }
+
+    /// Remembers which command is being aborted, which is the oldest one that didn't complete, since commands are executed in order.
+    /// Call this before setting Command Abort (CA).
+    pub fn start_abort(&mut self) {
+        self.aborting = self
+            .has_pending_commands()
+            .then(|| self.trb_phys_addr(self.next_command().0));
+    }
+
+    /// While a command is being aborted, the command doorbell shouldn't be rung, so that the xHC doesn't start the aborted command after it stopped
+    pub fn is_aborting(&self) -> bool {
+        self.aborting.is_some()
+    }
+
+    /// Returns `true` once after [`Self::process_event`] put commands on the ring that the xHC won't see until the command doorbell is rung.
+    /// That happens when the ring stopped because of an abort, and when commands were moved from the overflow queue.
+    pub fn take_doorbell_needed(&mut self) -> bool {
+        // During an abort, the Command Ring Stopped event decides if the ring is restarted
+        core::mem::take(&mut self.doorbell_needed) && !self.is_aborting()
+    }
+
+    /// `command_index` is the command TRB that the xHC got to, and `new_dequeue_pointer` is where it will continue from.
+    fn set_dequeue_pointer(&mut self, command_index: usize, new_dequeue_pointer: usize) {
+        log::debug!("Command ring dequeue pointer updated to {new_dequeue_pointer:X?}");
+        // If the consumer (xHC) looped around to get to the command, it must have toggled its consumer cycle state.
+        // We can't compare with the new dequeue pointer, because it can end up at the same index after going around (like in a ring with only 1 command TRB).
+        if command_index < self.dequeue_pointer {
+            self.consumer_cycle_state = !self.consumer_cycle_state;
+        }
+        self.dequeue_pointer = new_dequeue_pointer;
+    }
+
     /// We can update the dequeue pointer based on events from the event ring.
     /// The command completion event tells us which command was completed.
     /// So we can advance the dequeue pointer based on the physical address of the command that the xHC finished.
@@ -113,25 +354,219 @@ impl CommandRing2<'_> {
         // xHCI 3.3 Command Interface
         // > Commands are executed by the xHC in the order that they are placed on the Command Ring.
         if event.control.trb_type() == XhciTrbType::CmdCompletionEvent.into() {
-            let event: &XhciCommandCompletionEventTrb = transmute_ref!(event);
-            let command_index = (event.command_trb_pointer.command_trb_pointer()
-                - self.ring_mem.phys_addr) as usize
-                / size_of::<AnyTrb>();
+            let mut event: XhciCommandCompletionEventTrb = transmute!(*event);
+            let command_trb_pointer = event.command_trb_pointer.command_trb_pointer();
+            let Some(command_index) = self.trb_index(command_trb_pointer) else {
+                log::warn!(
+                    "xHCI - Got a Command Completion Event for {command_trb_pointer:#X}, which is not on the command ring"
+                );
+                return;
+            };
+
+            if event.status.completion_code() == CompletionCode::CommandRingStopped {
+                // xHCI 4.6.1.2 Aborting a Command
+                // > the Command TRB Pointer field of the Command Ring Stopped event shall point to the next Command TRB to be executed
+                // So the xHC didn't execute this command, and there's no completion for it yet.
+                self.set_dequeue_pointer(command_index, command_index);
+                if self.aborting.take() == Some(command_trb_pointer) {
+                    // The command we wanted to abort never started, so turn it into a No Op.
+                    // It will complete right away when the ring is restarted, without running the original command.
+                    let trb = self.trb_mut(command_index);
+                    let mut no_op: AnyTrb = NoOpCommandTrb::new().into();
+                    no_op.control.set_cycle_bit(trb.control.cycle_bit());
+                    *trb = no_op;
+                    self.aborted_as_no_op = Some(command_trb_pointer);
+                }
+                self.drain_overflow();
+                self.doorbell_needed = self.has_pending_commands();
+                return;
+            }
+
             // This could result in the dequeue pointer pointing to a Link TRB, which should be pretty instantly processed.
             // But we can't assume that the xHC processed the Link TRB and we shouldn't overwrite it until we're sure.
             // Since commands are executed in order, we don't need to worry about the dequeue pointer getting moved back because of out-of-order events.
-            let new_dequeue_pointer = command_index + 1;
-            log::debug!("Command ring dequeue pointer updated to {new_dequeue_pointer:X?}");
-            // If the consumer (xHC) looped around, it must have toggled its consumer cycle state
-            if new_dequeue_pointer < self.dequeue_pointer {
-                self.consumer_cycle_state = !self.consumer_cycle_state;
+            self.set_dequeue_pointer(command_index, command_index + 1);
+
+            if self.aborted_as_no_op == Some(command_trb_pointer) {
+                self.aborted_as_no_op = None;
+                event
+                    .status
+                    .set_completion_code(CompletionCode::CommandAborted);
             }
-            self.dequeue_pointer = new_dequeue_pointer;
+            match self.completions.remove(&command_trb_pointer) {
+                Some(CommandCompletion::Stored(_)) => {
+                    self.completions
+                        .insert(command_trb_pointer, CommandCompletion::Stored(Some(event)));
+                }
+                Some(CommandCompletion::Awaited(completer)) => completer.complete(event),
+                None => log::warn!(
+                    "xHCI - Got a Command Completion Event for {command_trb_pointer:#X}, which is not a command we enqueued"
+                ),
+            }
+            // Only after the completion was delivered, since a queued command can go in the TRB that just completed
+            self.drain_overflow();
         }
     }
+
+    /// Returns the Command Completion Event for the command at `trb_phys_addr` if it arrived, and forgets about the command
+    pub fn take_completion(&mut self, trb_phys_addr: u64) -> Option<XhciCommandCompletionEventTrb> {
+        let CommandCompletion::Stored(Some(completion)) = *self.completions.get(&trb_phys_addr)?
+        else {
+            return None;
+        };
+        self.completions.remove(&trb_phys_addr);
+        // The TRB can be reused now
+        self.drain_overflow();
+        Some(completion)
+    }
 }
 
 #[derive(Debug)]
 pub enum EnqueueError {
     IsFull,
 }
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    fn command_ring(segment_len: usize, segments: usize) -> CommandRing2<'static> {
+        CommandRing2::new(segment_len, segments, 0, u64::MAX, &mut TestAllocator).unwrap()
+    }
+
+    fn enqueue_no_op(command_ring: &mut CommandRing2) -> Result<u64, EnqueueError> {
+        command_ring.try_enqueue(
+            NoOpCommandTrb::new().into(),
+            CommandCompletion::Stored(None),
+        )
+    }
+
+    /// What the xHC would write to the event ring after getting to the command at `command_trb_pointer`
+    fn completion_event(command_trb_pointer: u64, completion_code: CompletionCode) -> AnyTrb {
+        let mut event = AnyTrb {
+            parameter: command_trb_pointer,
+            status: 0,
+            control: AnyTrbControl(0),
+        };
+        event
+            .control
+            .set_trb_type(XhciTrbType::CmdCompletionEvent.into());
+        let mut event: XhciCommandCompletionEventTrb = transmute!(event);
+        event.status.set_completion_code(completion_code);
+        transmute!(event)
+    }
+
+    fn complete(command_ring: &mut CommandRing2, command_trb_pointer: u64) {
+        command_ring.process_event(&completion_event(
+            command_trb_pointer,
+            CompletionCode::Success,
+        ));
+        let completion = command_ring.take_completion(command_trb_pointer).unwrap();
+        assert!(completion.result().is_ok());
+    }
+
+    #[test]
+    fn wraps_with_one_command_trb() {
+        let mut command_ring = command_ring(2, 1);
+        for _ in 0..4 {
+            let command = enqueue_no_op(&mut command_ring).unwrap();
+            assert!(enqueue_no_op(&mut command_ring).is_err());
+            complete(&mut command_ring, command);
+            assert!(!command_ring.has_pending_commands());
+        }
+        unsafe { command_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn wraps_across_segments() {
+        let mut command_ring = command_ring(3, 2);
+        for _ in 0..3 {
+            let commands = [(); 4].map(|_| enqueue_no_op(&mut command_ring).unwrap());
+            assert!(enqueue_no_op(&mut command_ring).is_err());
+            for command in commands {
+                complete(&mut command_ring, command);
+            }
+            assert!(!command_ring.has_pending_commands());
+        }
+        unsafe { command_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn aborts_the_command_after_a_link_trb() {
+        let mut command_ring = command_ring(3, 2);
+        // Fill the first segment, so the dequeue pointer ends up on its Link TRB
+        for _ in 0..2 {
+            let command = enqueue_no_op(&mut command_ring).unwrap();
+            complete(&mut command_ring, command);
+        }
+        let command = enqueue_no_op(&mut command_ring).unwrap();
+        command_ring.start_abort();
+        assert_eq!(command_ring.aborting, Some(command));
+        command_ring.process_event(&completion_event(
+            command,
+            CompletionCode::CommandRingStopped,
+        ));
+        assert!(!command_ring.is_aborting());
+        assert!(command_ring.take_doorbell_needed());
+        assert_eq!(
+            command_ring
+                .trb_mut(command_ring.dequeue_pointer)
+                .control
+                .trb_type(),
+            XhciTrbType::NoopCmd.into()
+        );
+        unsafe { command_ring.free(&mut TestAllocator) };
+    }
+
+    fn is_complete(future: &mut CommandFuture) -> bool {
+        let mut context = Context::from_waker(Waker::noop());
+        match Pin::new(future).poll(&mut context) {
+            Poll::Ready(result) => {
+                assert!(result.unwrap().result().is_ok());
+                true
+            }
+            Poll::Pending => false,
+        }
+    }
+
+    #[test]
+    fn overflow_queue_drains_in_order() {
+        let mut command_ring = CommandRing2::new(3, 1, 2, u64::MAX, &mut TestAllocator).unwrap();
+        let first = enqueue_no_op(&mut command_ring).unwrap();
+        let second = enqueue_no_op(&mut command_ring).unwrap();
+        let mut queue = || {
+            let (completer, future) = completion();
+            let result = command_ring.enqueue_or_queue(NoOpCommandTrb::new().into(), completer);
+            (result, future)
+        };
+        let (result, mut queued_first) = queue();
+        assert!(matches!(result, Ok(false)));
+        let (result, mut queued_second) = queue();
+        assert!(matches!(result, Ok(false)));
+        let (result, _) = queue();
+        assert!(result.is_err());
+
+        // Each completion frees a TRB, which the oldest queued command gets
+        complete(&mut command_ring, first);
+        assert!(command_ring.take_doorbell_needed());
+        assert!(!command_ring.take_doorbell_needed());
+        complete(&mut command_ring, second);
+        assert!(command_ring.take_doorbell_needed());
+
+        command_ring.process_event(&completion_event(first, CompletionCode::Success));
+        assert!(is_complete(&mut queued_first));
+        assert!(!is_complete(&mut queued_second));
+        // Nothing was moved onto the ring, so the xHC already knows about everything
+        assert!(!command_ring.take_doorbell_needed());
+        command_ring.process_event(&completion_event(second, CompletionCode::Success));
+        assert!(is_complete(&mut queued_second));
+        assert!(!command_ring.has_pending_commands());
+        unsafe { command_ring.free(&mut TestAllocator) };
+    }
+}
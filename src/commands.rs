@@ -0,0 +1,762 @@
+// xHCI 6.4.3 Command TRBs
+// Every command has its own struct with the same layout as the TRB, so it can be converted into an [`AnyTrb`] and put on the command ring.
+// The constructors set the TRB Type. The Cycle bit is set by the command ring when the command is enqueued.
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// A TRB that is allowed on the command ring
+pub trait Command: Into<AnyTrb> {
+    /// The physical address of memory that the caller gave the command for the xHC to access, if there is any.
+    /// The driver checks it against [`Driver::max_phys_addr`] before submitting the command.
+    fn pointer(&self) -> Option<u64> {
+        None
+    }
+}
+
+macro_rules! command {
+    ($command:ty) => {
+        impl From<$command> for AnyTrb {
+            fn from(value: $command) -> Self {
+                transmute!(value)
+            }
+        }
+
+        impl Command for $command {}
+    };
+    ($command:ty, $pointer:ident) => {
+        impl From<$command> for AnyTrb {
+            fn from(value: $command) -> Self {
+                transmute!(value)
+            }
+        }
+
+        impl Command for $command {
+            fn pointer(&self) -> Option<u64> {
+                Some(self.$pointer)
+            }
+        }
+    };
+}
+
+/// xHCI 6.4.3.1 No Op Command TRB
+/// Doesn't do anything except generate a Command Completion Event. Useful for testing the command ring.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct NoOpCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: NoOpCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct NoOpCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+}
+
+impl NoOpCommandTrb {
+    pub fn new() -> Self {
+        let mut control = NoOpCommandControl(0);
+        control.set_trb_type(XhciTrbType::NoopCmd.into());
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control,
+        }
+    }
+}
+
+impl Default for NoOpCommandTrb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+command!(NoOpCommandTrb);
+
+/// xHCI 6.4.3.2 Enable Slot Command TRB
+/// The Slot ID of the new Device Slot is in the Command Completion Event.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct EnableSlotCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: EnableSlotCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct EnableSlotCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// The Protocol Slot Type from the Supported Protocol Capability of the port that the device is on.
+    pub slot_type, set_slot_type: 20, 16;
+}
+
+impl EnableSlotCommandTrb {
+    pub fn new(slot_type: u8) -> Self {
+        let mut control = EnableSlotCommandControl(0);
+        control.set_trb_type(XhciTrbType::EnableSlotCmd.into());
+        control.set_slot_type(slot_type);
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control,
+        }
+    }
+}
+
+command!(EnableSlotCommandTrb);
+
+bitfield! {
+    /// The control field of commands that only have a Slot ID
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SlotCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+impl SlotCommandControl {
+    fn new(trb_type: XhciTrbType, slot_id: u8) -> Self {
+        let mut control = Self(0);
+        control.set_trb_type(trb_type.into());
+        control.set_slot_id(slot_id);
+        control
+    }
+}
+
+/// xHCI 6.4.3.3 Disable Slot Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct DisableSlotCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: SlotCommandControl,
+}
+
+impl DisableSlotCommandTrb {
+    pub fn new(slot_id: u8) -> Self {
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control: SlotCommandControl::new(XhciTrbType::DisableSlotCmd, slot_id),
+        }
+    }
+}
+
+command!(DisableSlotCommandTrb);
+
+bitfield! {
+    /// The control field of Address Device and Configure Endpoint, which have the same layout.
+    /// Bit 9 is Block Set Address Request (BSR) for Address Device, and Deconfigure (DC) for Configure Endpoint.
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct InputContextCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    pub bsr_or_dc, set_bsr_or_dc: 9;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+/// xHCI 6.4.3.4 Address Device Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct AddressDeviceCommandTrb {
+    pub input_context_pointer: u64,
+    _reserved_0: u32,
+    pub control: InputContextCommandControl,
+}
+
+impl AddressDeviceCommandTrb {
+    /// If `bsr` (Block Set Address Request) is set, the xHC doesn't send a SET_ADDRESS request to the device.
+    /// Some USB 2 devices need that so that the driver can read the first 8 bytes of the device descriptor before the device is addressed.
+    pub fn new(input_context: &InputContext, slot_id: u8, bsr: bool) -> Self {
+        let mut control = InputContextCommandControl(0);
+        control.set_trb_type(XhciTrbType::AddressDeviceCmd.into());
+        control.set_bsr_or_dc(bsr);
+        control.set_slot_id(slot_id);
+        Self {
+            input_context_pointer: input_context.phys_addr(),
+            _reserved_0: 0,
+            control,
+        }
+    }
+}
+
+command!(AddressDeviceCommandTrb);
+
+/// xHCI 6.4.3.5 Configure Endpoint Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct ConfigureEndpointCommandTrb {
+    /// Ignored by the xHC if Deconfigure (DC) is set
+    pub input_context_pointer: u64,
+    _reserved_0: u32,
+    pub control: InputContextCommandControl,
+}
+
+impl ConfigureEndpointCommandTrb {
+    pub fn new(input_context: &InputContext, slot_id: u8) -> Self {
+        let mut control = InputContextCommandControl(0);
+        control.set_trb_type(XhciTrbType::ConfigureEndpointCmd.into());
+        control.set_slot_id(slot_id);
+        Self {
+            input_context_pointer: input_context.phys_addr(),
+            _reserved_0: 0,
+            control,
+        }
+    }
+
+    /// Deconfigure (DC): removes every endpoint except the Default Control Endpoint, and doesn't use an Input Context
+    pub fn deconfigure(slot_id: u8) -> Self {
+        let mut control = InputContextCommandControl(0);
+        control.set_trb_type(XhciTrbType::ConfigureEndpointCmd.into());
+        control.set_bsr_or_dc(true);
+        control.set_slot_id(slot_id);
+        Self {
+            input_context_pointer: 0,
+            _reserved_0: 0,
+            control,
+        }
+    }
+}
+
+command!(ConfigureEndpointCommandTrb);
+
+/// xHCI 6.4.3.6 Evaluate Context Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct EvaluateContextCommandTrb {
+    pub input_context_pointer: u64,
+    _reserved_0: u32,
+    pub control: SlotCommandControl,
+}
+
+impl EvaluateContextCommandTrb {
+    pub fn new(input_context: &InputContext, slot_id: u8) -> Self {
+        Self {
+            input_context_pointer: input_context.phys_addr(),
+            _reserved_0: 0,
+            control: SlotCommandControl::new(XhciTrbType::EvaluateContextCmd, slot_id),
+        }
+    }
+}
+
+command!(EvaluateContextCommandTrb);
+
+/// xHCI 6.4.3.7 Reset Endpoint Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct ResetEndpointCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: ResetEndpointCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ResetEndpointCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    /// Transfer State Preserve (TSP)
+    pub tsp, set_tsp: 9;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// The Device Context Index (DCI) of the endpoint
+    pub endpoint_id, set_endpoint_id: 20, 16;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+impl ResetEndpointCommandTrb {
+    /// If `tsp` (Transfer State Preserve) is set, the xHC keeps the endpoint's transfer state, so the last transfer can be retried.
+    pub fn new(slot_id: u8, endpoint_id: u8, tsp: bool) -> Self {
+        let mut control = ResetEndpointCommandControl(0);
+        control.set_trb_type(XhciTrbType::ResetEndpointCmd.into());
+        control.set_tsp(tsp);
+        control.set_endpoint_id(endpoint_id);
+        control.set_slot_id(slot_id);
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control,
+        }
+    }
+}
+
+command!(ResetEndpointCommandTrb);
+
+/// xHCI 6.4.3.8 Stop Endpoint Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct StopEndpointCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: StopEndpointCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct StopEndpointCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// The Device Context Index (DCI) of the endpoint
+    pub endpoint_id, set_endpoint_id: 20, 16;
+    /// Suspend (SP): the endpoint is being stopped because the device is going to be suspended
+    pub suspend, set_suspend: 23;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+impl StopEndpointCommandTrb {
+    pub fn new(slot_id: u8, endpoint_id: u8, suspend: bool) -> Self {
+        let mut control = StopEndpointCommandControl(0);
+        control.set_trb_type(XhciTrbType::StopEndpointCmd.into());
+        control.set_endpoint_id(endpoint_id);
+        control.set_suspend(suspend);
+        control.set_slot_id(slot_id);
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control,
+        }
+    }
+}
+
+command!(StopEndpointCommandTrb);
+
+/// xHCI 6.4.3.9 Set TR Dequeue Pointer Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct SetTrDequeuePointerCommandTrb {
+    pub dequeue_pointer: SetTrDequeuePointer,
+    pub status: SetTrDequeuePointerStatus,
+    pub control: SetTrDequeuePointerCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetTrDequeuePointer(u64);
+    impl Debug;
+
+    /// Dequeue Cycle State (DCS)
+    pub dcs, set_dcs: 0;
+    u8;
+    /// Stream Context Type (SCT), only used if the endpoint has streams
+    pub sct, set_sct: 3, 1;
+    u64; _new_tr_dequeue_pointer, _set_new_tr_dequeue_pointer: 63, 4;
+}
+
+impl SetTrDequeuePointer {
+    pub fn new_tr_dequeue_pointer(&self) -> u64 {
+        self._new_tr_dequeue_pointer() << 4
+    }
+
+    pub fn set_new_tr_dequeue_pointer(&mut self, new_tr_dequeue_pointer: u64) {
+        self._set_new_tr_dequeue_pointer(new_tr_dequeue_pointer >> 4);
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetTrDequeuePointerStatus(u32);
+    impl Debug;
+
+    u16; pub stream_id, set_stream_id: 31, 16;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetTrDequeuePointerCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// The Device Context Index (DCI) of the endpoint
+    pub endpoint_id, set_endpoint_id: 20, 16;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+impl SetTrDequeuePointerCommandTrb {
+    /// `new_tr_dequeue_pointer` has to be 16 byte aligned.
+    /// `dcs` is the cycle state that the xHC should expect at the new dequeue pointer.
+    pub fn new(
+        slot_id: u8,
+        endpoint_id: u8,
+        stream_id: u16,
+        new_tr_dequeue_pointer: u64,
+        dcs: bool,
+    ) -> Self {
+        let mut dequeue_pointer = SetTrDequeuePointer(0);
+        dequeue_pointer.set_new_tr_dequeue_pointer(new_tr_dequeue_pointer);
+        dequeue_pointer.set_dcs(dcs);
+        let mut status = SetTrDequeuePointerStatus(0);
+        status.set_stream_id(stream_id);
+        let mut control = SetTrDequeuePointerCommandControl(0);
+        control.set_trb_type(XhciTrbType::SetTrDequeuePtrCmd.into());
+        control.set_endpoint_id(endpoint_id);
+        control.set_slot_id(slot_id);
+        Self {
+            dequeue_pointer,
+            status,
+            control,
+        }
+    }
+}
+
+command!(SetTrDequeuePointerCommandTrb);
+
+/// xHCI 6.4.3.10 Reset Device Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct ResetDeviceCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: SlotCommandControl,
+}
+
+impl ResetDeviceCommandTrb {
+    pub fn new(slot_id: u8) -> Self {
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control: SlotCommandControl::new(XhciTrbType::ResetDeviceCmd, slot_id),
+        }
+    }
+}
+
+command!(ResetDeviceCommandTrb);
+
+/// xHCI 6.4.3.11 Force Event Command TRB
+/// Only used with virtualization, to put an event on a Virtual Function's event ring.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct ForceEventCommandTrb {
+    /// Has to be 16 byte aligned, and the xHC has to be able to access it (see [`Driver::max_phys_addr`])
+    pub event_trb_pointer: u64,
+    pub status: ForceEventCommandStatus,
+    pub control: ForceEventCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ForceEventCommandStatus(u32);
+    impl Debug;
+
+    u16; pub vf_interrupter_target, set_vf_interrupter_target: 31, 22;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ForceEventCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8; pub vf_id, set_vf_id: 23, 16;
+}
+
+impl ForceEventCommandTrb {
+    pub fn new(event_trb_pointer: u64, vf_interrupter_target: u16, vf_id: u8) -> Self {
+        let mut status = ForceEventCommandStatus(0);
+        status.set_vf_interrupter_target(vf_interrupter_target);
+        let mut control = ForceEventCommandControl(0);
+        control.set_trb_type(XhciTrbType::ForceEventCmd.into());
+        control.set_vf_id(vf_id);
+        Self {
+            event_trb_pointer,
+            status,
+            control,
+        }
+    }
+}
+
+command!(ForceEventCommandTrb, event_trb_pointer);
+
+/// xHCI 6.4.3.12 Negotiate Bandwidth Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct NegotiateBandwidthCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: SlotCommandControl,
+}
+
+impl NegotiateBandwidthCommandTrb {
+    pub fn new(slot_id: u8) -> Self {
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control: SlotCommandControl::new(XhciTrbType::NegotiateBandwidthCmd, slot_id),
+        }
+    }
+}
+
+command!(NegotiateBandwidthCommandTrb);
+
+/// xHCI 6.4.3.13 Set Latency Tolerance Value Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct SetLatencyToleranceValueCommandTrb {
+    _reserved_0: u64,
+    _reserved_1: u32,
+    pub control: SetLatencyToleranceValueCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetLatencyToleranceValueCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u16;
+    /// Best Effort Latency Tolerance (BELT), in the same format as the LTM BELT in the USB 3 spec
+    pub belt, set_belt: 27, 16;
+}
+
+impl SetLatencyToleranceValueCommandTrb {
+    pub fn new(belt: u16) -> Self {
+        let mut control = SetLatencyToleranceValueCommandControl(0);
+        control.set_trb_type(XhciTrbType::SetLatencyToleranceValueCmd.into());
+        control.set_belt(belt);
+        Self {
+            _reserved_0: 0,
+            _reserved_1: 0,
+            control,
+        }
+    }
+}
+
+command!(SetLatencyToleranceValueCommandTrb);
+
+/// xHCI 6.4.3.14 Get Port Bandwidth Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct GetPortBandwidthCommandTrb {
+    /// Where the xHC writes the Port Bandwidth Context (xHCI 6.2.6). Has to be 16 byte aligned, and not past [`Driver::max_phys_addr`].
+    pub port_bandwidth_context_pointer: u64,
+    _reserved_0: u32,
+    pub control: GetPortBandwidthCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct GetPortBandwidthCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// The Protocol Speed ID (PSI) to get the available bandwidth for
+    pub dev_speed, set_dev_speed: 19, 16;
+    u8;
+    /// 0 for the root hub, or the Slot ID of a hub
+    pub hub_slot_id, set_hub_slot_id: 31, 24;
+}
+
+impl GetPortBandwidthCommandTrb {
+    pub fn new(port_bandwidth_context_pointer: u64, dev_speed: u8, hub_slot_id: u8) -> Self {
+        let mut control = GetPortBandwidthCommandControl(0);
+        control.set_trb_type(XhciTrbType::GetPortBandwidthCmd.into());
+        control.set_dev_speed(dev_speed);
+        control.set_hub_slot_id(hub_slot_id);
+        Self {
+            port_bandwidth_context_pointer,
+            _reserved_0: 0,
+            control,
+        }
+    }
+}
+
+command!(GetPortBandwidthCommandTrb, port_bandwidth_context_pointer);
+
+/// xHCI 6.4.3.15 Force Header Command TRB
+/// Makes a root hub port send a USB 3 packet header, which is mostly useful for testing.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct ForceHeaderCommandTrb {
+    pub header_lo: ForceHeaderLo,
+    pub header_hi: u32,
+    pub control: ForceHeaderCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ForceHeaderLo(u64);
+    impl Debug;
+
+    u8; pub packet_type, set_packet_type: 4, 0;
+    u64;
+    /// Header Info Lo (bits 31:5) and Header Info Mid (bits 63:32)
+    pub header_info, set_header_info: 63, 5;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ForceHeaderCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8; pub root_hub_port_number, set_root_hub_port_number: 31, 24;
+}
+
+impl ForceHeaderCommandTrb {
+    /// `header_info` is bits 5-95 of the header, the first 5 bits are the `packet_type`
+    pub fn new(
+        root_hub_port_number: u8,
+        packet_type: u8,
+        header_info: u64,
+        header_hi: u32,
+    ) -> Self {
+        let mut header_lo = ForceHeaderLo(0);
+        header_lo.set_packet_type(packet_type);
+        header_lo.set_header_info(header_info);
+        let mut control = ForceHeaderCommandControl(0);
+        control.set_trb_type(XhciTrbType::ForceHeaderCmd.into());
+        control.set_root_hub_port_number(root_hub_port_number);
+        Self {
+            header_lo,
+            header_hi,
+            control,
+        }
+    }
+}
+
+command!(ForceHeaderCommandTrb);
+
+bitfield! {
+    /// The control field of Get Extended Property and Set Extended Property, which have the same layout
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct ExtendedPropertyCommandControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8; pub command_sub_type, set_command_sub_type: 18, 16;
+    u8;
+    /// The Device Context Index (DCI) of the endpoint, or 0 if the property isn't for an endpoint
+    pub endpoint_id, set_endpoint_id: 23, 19;
+    u8; pub slot_id, set_slot_id: 31, 24;
+}
+
+impl ExtendedPropertyCommandControl {
+    fn new(trb_type: XhciTrbType, command_sub_type: u8, endpoint_id: u8, slot_id: u8) -> Self {
+        let mut control = Self(0);
+        control.set_trb_type(trb_type.into());
+        control.set_command_sub_type(command_sub_type);
+        control.set_endpoint_id(endpoint_id);
+        control.set_slot_id(slot_id);
+        control
+    }
+}
+
+/// xHCI 6.4.3.16 Get Extended Property Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct GetExtendedPropertyCommandTrb {
+    /// Where the xHC writes the Extended Property Context. Has to be 16 byte aligned, and not past [`Driver::max_phys_addr`].
+    pub extended_property_context_pointer: u64,
+    pub status: GetExtendedPropertyCommandStatus,
+    pub control: ExtendedPropertyCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct GetExtendedPropertyCommandStatus(u32);
+    impl Debug;
+
+    u16;
+    /// Extended Capability Identifier (ECI)
+    pub eci, set_eci: 15, 0;
+}
+
+impl GetExtendedPropertyCommandTrb {
+    pub fn new(
+        extended_property_context_pointer: u64,
+        eci: u16,
+        command_sub_type: u8,
+        endpoint_id: u8,
+        slot_id: u8,
+    ) -> Self {
+        let mut status = GetExtendedPropertyCommandStatus(0);
+        status.set_eci(eci);
+        Self {
+            extended_property_context_pointer,
+            status,
+            control: ExtendedPropertyCommandControl::new(
+                XhciTrbType::GetExtendedPropertyCmd,
+                command_sub_type,
+                endpoint_id,
+                slot_id,
+            ),
+        }
+    }
+}
+
+command!(
+    GetExtendedPropertyCommandTrb,
+    extended_property_context_pointer
+);
+
+/// xHCI 6.4.3.17 Set Extended Property Command TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct SetExtendedPropertyCommandTrb {
+    _reserved_0: u64,
+    pub status: SetExtendedPropertyCommandStatus,
+    pub control: ExtendedPropertyCommandControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetExtendedPropertyCommandStatus(u32);
+    impl Debug;
+
+    u16;
+    /// Extended Capability Identifier (ECI)
+    pub eci, set_eci: 15, 0;
+    u8; pub capability_parameter, set_capability_parameter: 23, 16;
+}
+
+impl SetExtendedPropertyCommandTrb {
+    pub fn new(
+        eci: u16,
+        capability_parameter: u8,
+        command_sub_type: u8,
+        endpoint_id: u8,
+        slot_id: u8,
+    ) -> Self {
+        let mut status = SetExtendedPropertyCommandStatus(0);
+        status.set_eci(eci);
+        status.set_capability_parameter(capability_parameter);
+        Self {
+            _reserved_0: 0,
+            status,
+            control: ExtendedPropertyCommandControl::new(
+                XhciTrbType::SetExtendedPropertyCmd,
+                command_sub_type,
+                endpoint_id,
+                slot_id,
+            ),
+        }
+    }
+}
+
+command!(SetExtendedPropertyCommandTrb);
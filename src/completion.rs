@@ -0,0 +1,126 @@
+// Futures that are completed from `Driver::handle_interrupt`.
+// The driver keeps a `Completer` and gives the `EventFuture` to the caller. They share an `Arc`, so neither one has to borrow the driver.
+// Nothing here takes a lock, so completing a future from an interrupt handler can't deadlock with a task that is polling it.
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use atomic_waker::AtomicWaker;
+
+use crate::*;
+
+/// Resolves to the Command Completion Event of a command from [`Driver::submit_command_async`]
+pub type CommandFuture = EventFuture<XhciCommandCompletionEventTrb>;
+/// Resolves to the Transfer Event of a transfer from [`Driver::control_transfer`] or [`Driver::normal_transfer`]
+pub type TransferFuture = EventFuture<XhciTransferEventTrb>;
+/// Resolves when the port reset from [`Driver::reset_port_async`] is done
+pub type PortResetFuture = EventFuture<()>;
+
+const PENDING: u8 = 0;
+const COMPLETE: u8 = 1;
+const CANCELLED: u8 = 2;
+
+struct Shared<T> {
+    state: AtomicU8,
+    /// Written by the [`Completer`] before `state` becomes [`COMPLETE`], and only read by the [`EventFuture`] after that
+    value: UnsafeCell<Option<T>>,
+    waker: AtomicWaker,
+}
+
+// Safety: `value` is only accessed by one side at a time, and `state` orders the accesses
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Creates a future and the [`Completer`] that the driver uses to complete it
+pub(crate) fn completion<T>() -> (Completer<T>, EventFuture<T>) {
+    let shared = Arc::new(Shared {
+        state: AtomicU8::new(PENDING),
+        value: UnsafeCell::new(None),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Completer {
+            shared: shared.clone(),
+        },
+        EventFuture { shared },
+    )
+}
+
+/// The driver's side of an [`EventFuture`].
+/// Dropping it without calling [`Self::complete`] makes the future resolve to [`Cancelled`].
+pub(crate) struct Completer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Completer<T> {
+    pub fn complete(self, value: T) {
+        // The future doesn't touch `value` until it sees `COMPLETE`
+        unsafe { *self.shared.value.get() = Some(value) };
+        self.shared.state.store(COMPLETE, Ordering::Release);
+        self.shared.waker.wake();
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if self.shared.state.load(Ordering::Acquire) == PENDING {
+            self.shared.state.store(CANCELLED, Ordering::Release);
+            self.shared.waker.wake();
+        }
+    }
+}
+
+impl<T> core::fmt::Debug for Completer<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Completer").finish_non_exhaustive()
+    }
+}
+
+/// A future that is woken by [`Driver::handle_interrupt`] when the event it's waiting for arrives.
+/// It only uses the [`Waker`](core::task::Waker) from the [`Context`], so it works with any executor.
+/// The waker is called from inside [`Driver::handle_interrupt`], so waking a task must be safe to do from your interrupt handler.
+///
+/// Dropping the future doesn't cancel the operation on the xHC, the event is just thrown away when it arrives.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct EventFuture<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Future for EventFuture<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register first, so that a completion that happens right after checking the state still wakes us
+        self.shared.waker.register(cx.waker());
+        match self.shared.state.load(Ordering::Acquire) {
+            PENDING => Poll::Pending,
+            COMPLETE => {
+                // The completer is done with `value`, and `&mut self` makes sure we're the only one taking it
+                let value = unsafe { (*self.shared.value.get()).take() };
+                Poll::Ready(Ok(
+                    value.expect("EventFuture polled after it returned Ready")
+                ))
+            }
+            _ => Poll::Ready(Err(Cancelled)),
+        }
+    }
+}
+
+impl<T> core::fmt::Debug for EventFuture<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EventFuture")
+            .field("state", &self.shared.state.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The driver forgot about the operation before its event arrived.
+/// This happens when the xHC is reinitialized (like in [`Driver::recover`]), suspended, or shut down,
+/// and when a transfer ring is freed while it still has transfers on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
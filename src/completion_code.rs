@@ -0,0 +1,189 @@
+/// xHCI 6.4.5 TRB Completion Codes
+/// Every event TRB has one of these. Refer to the TRB that caused the event for which ones it can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionCode {
+    /// Indicates that the Completion Code field has not been updated by the TRB producer
+    Invalid,
+    /// Indicates successful completion of the TRB operation
+    Success,
+    /// Overrun or underrun of the data buffer
+    DataBufferError,
+    /// The device kept sending data after the end of a packet (babble)
+    BabbleDetectedError,
+    /// No valid response from the device (timeout, CRC error, bad PID, ...)
+    UsbTransactionError,
+    /// A TRB parameter is wrong, or the TRB is not allowed on this ring
+    TrbError,
+    /// The device answered with a STALL. The endpoint is halted.
+    StallError,
+    /// The xHC doesn't have the internal resources that the command needs
+    ResourceError,
+    /// There isn't enough bandwidth for the endpoints in a Configure Endpoint Command
+    BandwidthError,
+    /// Every Device Slot is already enabled
+    NoSlotsAvailableError,
+    /// The Stream Context Type in a Stream Context is invalid
+    InvalidStreamTypeError,
+    /// The command or doorbell is for a Device Slot that is not enabled
+    SlotNotEnabledError,
+    /// The doorbell is for an endpoint that is not enabled
+    EndpointNotEnabledError,
+    /// The transfer was shorter than the TRB Transfer Length
+    ShortPacket,
+    /// An isochronous OUT endpoint had nothing to send
+    RingUnderrun,
+    /// An isochronous IN endpoint had nowhere to put the data
+    RingOverrun,
+    /// A Virtual Function's event ring is full (Force Event Command)
+    VfEventRingFullError,
+    /// A context parameter is invalid
+    ParameterError,
+    /// An endpoint used more bandwidth than it asked for
+    BandwidthOverrunError,
+    /// The command isn't allowed in the current state of the Slot or Endpoint Context
+    ContextStateError,
+    /// The device didn't answer a PING in time
+    NoPingResponseError,
+    /// The event ring was full, so the xHC dropped events
+    EventRingFullError,
+    /// A device that isn't compatible with the xHC was connected (for example a LS/FS device behind a HS hub with a TT problem)
+    IncompatibleDeviceError,
+    /// An isochronous transfer missed its service interval
+    MissedServiceError,
+    /// The command ring was stopped (with CS or CA in CRCR)
+    CommandRingStopped,
+    /// The command was aborted (with CA in CRCR)
+    CommandAborted,
+    /// The transfer was stopped by a Stop Endpoint Command
+    Stopped,
+    /// Same as [`Self::Stopped`], but the TRB Transfer Length is not valid
+    StoppedLengthInvalid,
+    /// Same as [`Self::Stopped`], but the transfer was stopped after a Short Packet
+    StoppedShortPacket,
+    /// The Max Exit Latency is too big for the links to the device
+    MaxExitLatencyTooLargeError,
+    /// An isochronous IN buffer was too small
+    IsochBufferOverrun,
+    /// The xHC lost events because of an internal error
+    EventLostError,
+    /// An error that doesn't have its own completion code
+    UndefinedError,
+    /// The Stream ID is not valid
+    InvalidStreamIdError,
+    /// There isn't enough bandwidth on a secondary bus (like behind a TT)
+    SecondaryBandwidthError,
+    /// A split transaction failed
+    SplitTransactionError,
+    /// A code that the spec reserves (30 and 37 to 191)
+    Reserved(u8),
+    /// 192 to 223: errors that the xHC vendor defined
+    VendorDefinedError(u8),
+    /// 224 to 255: information that the xHC vendor defined
+    VendorDefinedInfo(u8),
+}
+
+impl From<u8> for CompletionCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Invalid,
+            1 => Self::Success,
+            2 => Self::DataBufferError,
+            3 => Self::BabbleDetectedError,
+            4 => Self::UsbTransactionError,
+            5 => Self::TrbError,
+            6 => Self::StallError,
+            7 => Self::ResourceError,
+            8 => Self::BandwidthError,
+            9 => Self::NoSlotsAvailableError,
+            10 => Self::InvalidStreamTypeError,
+            11 => Self::SlotNotEnabledError,
+            12 => Self::EndpointNotEnabledError,
+            13 => Self::ShortPacket,
+            14 => Self::RingUnderrun,
+            15 => Self::RingOverrun,
+            16 => Self::VfEventRingFullError,
+            17 => Self::ParameterError,
+            18 => Self::BandwidthOverrunError,
+            19 => Self::ContextStateError,
+            20 => Self::NoPingResponseError,
+            21 => Self::EventRingFullError,
+            22 => Self::IncompatibleDeviceError,
+            23 => Self::MissedServiceError,
+            24 => Self::CommandRingStopped,
+            25 => Self::CommandAborted,
+            26 => Self::Stopped,
+            27 => Self::StoppedLengthInvalid,
+            28 => Self::StoppedShortPacket,
+            29 => Self::MaxExitLatencyTooLargeError,
+            31 => Self::IsochBufferOverrun,
+            32 => Self::EventLostError,
+            33 => Self::UndefinedError,
+            34 => Self::InvalidStreamIdError,
+            35 => Self::SecondaryBandwidthError,
+            36 => Self::SplitTransactionError,
+            192..=223 => Self::VendorDefinedError(value),
+            224..=255 => Self::VendorDefinedInfo(value),
+            _ => Self::Reserved(value),
+        }
+    }
+}
+
+impl From<CompletionCode> for u8 {
+    fn from(value: CompletionCode) -> Self {
+        match value {
+            CompletionCode::Invalid => 0,
+            CompletionCode::Success => 1,
+            CompletionCode::DataBufferError => 2,
+            CompletionCode::BabbleDetectedError => 3,
+            CompletionCode::UsbTransactionError => 4,
+            CompletionCode::TrbError => 5,
+            CompletionCode::StallError => 6,
+            CompletionCode::ResourceError => 7,
+            CompletionCode::BandwidthError => 8,
+            CompletionCode::NoSlotsAvailableError => 9,
+            CompletionCode::InvalidStreamTypeError => 10,
+            CompletionCode::SlotNotEnabledError => 11,
+            CompletionCode::EndpointNotEnabledError => 12,
+            CompletionCode::ShortPacket => 13,
+            CompletionCode::RingUnderrun => 14,
+            CompletionCode::RingOverrun => 15,
+            CompletionCode::VfEventRingFullError => 16,
+            CompletionCode::ParameterError => 17,
+            CompletionCode::BandwidthOverrunError => 18,
+            CompletionCode::ContextStateError => 19,
+            CompletionCode::NoPingResponseError => 20,
+            CompletionCode::EventRingFullError => 21,
+            CompletionCode::IncompatibleDeviceError => 22,
+            CompletionCode::MissedServiceError => 23,
+            CompletionCode::CommandRingStopped => 24,
+            CompletionCode::CommandAborted => 25,
+            CompletionCode::Stopped => 26,
+            CompletionCode::StoppedLengthInvalid => 27,
+            CompletionCode::StoppedShortPacket => 28,
+            CompletionCode::MaxExitLatencyTooLargeError => 29,
+            CompletionCode::IsochBufferOverrun => 31,
+            CompletionCode::EventLostError => 32,
+            CompletionCode::UndefinedError => 33,
+            CompletionCode::InvalidStreamIdError => 34,
+            CompletionCode::SecondaryBandwidthError => 35,
+            CompletionCode::SplitTransactionError => 36,
+            CompletionCode::Reserved(value)
+            | CompletionCode::VendorDefinedError(value)
+            | CompletionCode::VendorDefinedInfo(value) => value,
+        }
+    }
+}
+
+impl CompletionCode {
+    /// `Ok` only for [`CompletionCode::Success`], so you can use `?` on the result of a command
+    pub fn into_result(self) -> Result<(), CompletionError> {
+        match self {
+            Self::Success => Ok(()),
+            code => Err(CompletionError(code)),
+        }
+    }
+}
+
+/// A [`CompletionCode`] that means the command or transfer didn't succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompletionError(pub CompletionCode);
@@ -0,0 +1,196 @@
+use core::{num::NonZero, time::Duration};
+
+use crate::*;
+
+/// How the driver should set up the xHC. Start with [`DriverConfig::default`] and change what you need.
+/// Everything is checked against the limits in the capability registers in [`Driver::new`].
+#[derive(Debug, Clone, Copy)]
+pub struct DriverConfig {
+    command_ring_len: usize,
+    command_ring_segments: usize,
+    command_overflow_queue_len: usize,
+    event_ring_segment_len: usize,
+    event_ring_segments: usize,
+    max_slots_en: Option<u8>,
+    interrupters: u16,
+    moderation_interval: Duration,
+    polling_mode: bool,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            command_ring_len: 256,
+            command_ring_segments: 1,
+            command_overflow_queue_len: 0,
+            event_ring_segment_len: 256,
+            event_ring_segments: 1,
+            max_slots_en: None,
+            interrupters: 1,
+            moderation_interval: Duration::ZERO,
+            polling_mode: false,
+        }
+    }
+}
+
+impl DriverConfig {
+    /// The number of TRBs in each command ring segment, including the Link TRB.
+    /// Must be between 2 and 4096 (a command ring segment can't be bigger than 64 KiB).
+    pub fn command_ring_len(mut self, len: usize) -> Self {
+        self.command_ring_len = len;
+        self
+    }
+
+    /// The number of segments in the command ring. Each one is a separate allocation,
+    /// so this is how to get a command ring that holds more than 4095 commands.
+    pub fn command_ring_segments(mut self, segments: usize) -> Self {
+        self.command_ring_segments = segments;
+        self
+    }
+
+    /// The number of commands from [`Driver::submit_command_async`] that can wait in memory while the command ring is full.
+    /// They are put on the command ring as the xHC completes commands. 0 (the default) means there is no queue.
+    pub fn command_overflow_queue_len(mut self, len: usize) -> Self {
+        self.command_overflow_queue_len = len;
+        self
+    }
+
+    /// The number of TRBs in each event ring segment.
+    /// xHCI 6.5 says this must be between 16 and 4096.
+    pub fn event_ring_segment_len(mut self, len: usize) -> Self {
+        self.event_ring_segment_len = len;
+        self
+    }
+
+    /// The number of segments in each event ring. Can't be more than 2^ERST Max (HCSPARAMS2),
+    /// and the Event Ring Segment Table has to fit in one page (256 segments with 4 KiB pages).
+    pub fn event_ring_segments(mut self, segments: usize) -> Self {
+        self.event_ring_segments = segments;
+        self
+    }
+
+    /// The number of device slots to enable (MaxSlotsEn in CONFIG). Defaults to MaxSlots from HCSPARAMS1.
+    pub fn max_slots_en(mut self, max_slots_en: u8) -> Self {
+        self.max_slots_en = Some(max_slots_en);
+        self
+    }
+
+    /// The number of interrupters to set up. Can't be more than MaxIntrs (HCSPARAMS1).
+    pub fn interrupters(mut self, interrupters: u16) -> Self {
+        self.interrupters = interrupters;
+        self
+    }
+
+    /// The minimum time between interrupts (IMODI in the IMOD register), which the xHC counts in 250 ns steps.
+    /// The default is 0, which doesn't throttle interrupts at all.
+    /// This is used for every interrupter. Use [`Driver::set_moderation_interval`] to change it for one interrupter later.
+    pub fn moderation_interval(mut self, moderation_interval: Duration) -> Self {
+        self.moderation_interval = moderation_interval;
+        self
+    }
+
+    /// Keeps interrupts disabled (INTE and IE stay 0), for environments that can't take interrupts, like bootloaders.
+    /// Use [`Driver::poll`] instead of [`Driver::handle_interrupt`] to process events.
+    pub fn polling_mode(mut self, polling_mode: bool) -> Self {
+        self.polling_mode = polling_mode;
+        self
+    }
+
+    /// Checks the config against the xHC's capabilities, and fills in the defaults that depend on the xHC
+    pub(crate) fn validate(
+        &self,
+        hcs_params_1: HcsParams1,
+        hcs_params_2: HcsParams2,
+        page_size: NonZero<u64>,
+    ) -> Result<ValidatedConfig, ConfigError> {
+        if !(2..=MAX_COMMAND_RING_LEN).contains(&self.command_ring_len) {
+            return Err(ConfigError::CommandRingLen);
+        }
+        if self.command_ring_segments == 0 {
+            return Err(ConfigError::CommandRingSegments);
+        }
+        if !(MIN_EVENT_RING_SEGMENT_LEN..=MAX_EVENT_RING_SEGMENT_LEN)
+            .contains(&self.event_ring_segment_len)
+        {
+            return Err(ConfigError::EventRingSegmentLen);
+        }
+        // xHCI 5.3.4: the maximum number of ERST entries is 2^ERST Max
+        let erst_max = 1 << hcs_params_2.erst_max();
+        if !(1..=erst_max).contains(&self.event_ring_segments) {
+            return Err(ConfigError::EventRingSegments);
+        }
+        // The ERST can't cross a page boundary, so it can't be bigger than a page either
+        if (self.event_ring_segments * size_of::<XhciErstEntry>()) as u64
+            > xhci_event_ring_segment_table_boundary(page_size).get()
+        {
+            return Err(ConfigError::EventRingSegmentTableTooBig);
+        }
+        let max_slots_en = self.max_slots_en.unwrap_or(hcs_params_1.max_slots());
+        if !(1..=hcs_params_1.max_slots()).contains(&max_slots_en) {
+            return Err(ConfigError::MaxSlotsEn);
+        }
+        if !(1..=hcs_params_1.max_interrupters()).contains(&self.interrupters) {
+            return Err(ConfigError::Interrupters);
+        }
+        let imodi =
+            imodi_from_duration(self.moderation_interval).ok_or(ConfigError::ModerationInterval)?;
+        Ok(ValidatedConfig {
+            command_ring_len: self.command_ring_len,
+            command_ring_segments: self.command_ring_segments,
+            command_overflow_queue_len: self.command_overflow_queue_len,
+            event_ring_segment_len: self.event_ring_segment_len,
+            event_ring_segments: self.event_ring_segments,
+            max_slots_en,
+            interrupters: self.interrupters,
+            imodi,
+            polling_mode: self.polling_mode,
+        })
+    }
+}
+
+/// A [`DriverConfig`] that fits the xHC
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ValidatedConfig {
+    pub command_ring_len: usize,
+    pub command_ring_segments: usize,
+    pub command_overflow_queue_len: usize,
+    pub event_ring_segment_len: usize,
+    pub event_ring_segments: usize,
+    pub max_slots_en: u8,
+    pub interrupters: u16,
+    pub imodi: u16,
+    pub polling_mode: bool,
+}
+
+/// A command ring segment can't be bigger than 64 KiB, which is 4096 TRBs
+const MAX_COMMAND_RING_LEN: usize =
+    XHCI_COMMAND_RING_SEGMENTS_MAX_SIZE.get() as usize / size_of::<AnyTrb>();
+/// Same as [`MAX_COMMAND_RING_LEN`], and also the maximum Ring Segment Size in xHCI 6.5
+const MAX_EVENT_RING_SEGMENT_LEN: usize =
+    XHCI_EVENT_RING_SEGMENTS_MAX_SIZE.get() as usize / size_of::<AnyTrb>();
+/// xHCI 6.5 Event Ring Segment Table: Ring Segment Size must be at least 16
+const MIN_EVENT_RING_SEGMENT_LEN: usize = 16;
+
+/// xHCI 5.5.2.2: IMODI is in 250 ns increments
+const IMODI_STEP: Duration = Duration::from_nanos(250);
+
+/// Returns `None` if the interval is too long to fit in IMODI (about 16 ms)
+pub(crate) fn imodi_from_duration(interval: Duration) -> Option<u16> {
+    (interval.as_nanos() / IMODI_STEP.as_nanos())
+        .try_into()
+        .ok()
+}
+
+/// A value in [`DriverConfig`] is outside of what the xHC (or the spec) allows
+#[derive(Debug)]
+pub enum ConfigError {
+    CommandRingLen,
+    CommandRingSegments,
+    EventRingSegmentLen,
+    EventRingSegments,
+    /// The Event Ring Segment Table for [`DriverConfig::event_ring_segments`] doesn't fit in one page
+    EventRingSegmentTableTooBig,
+    MaxSlotsEn,
+    Interrupters,
+    ModerationInterval,
+}
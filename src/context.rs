@@ -0,0 +1,410 @@
+use core::num::NonZero;
+
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::*;
+
+/// xHCI 5.3.6 Capability Parameters 1 (HCCPARAMS1) Context Size (CSZ)
+/// Every context structure is defined as 32 bytes, but if CSZ is set the xHC uses 64 bytes for each one, and the extra 32 bytes are reserved.
+/// Getting this wrong makes the xHC read and write the wrong parts of the Device Context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextSize {
+    Bytes32,
+    Bytes64,
+}
+
+impl ContextSize {
+    pub fn from_csz(csz: bool) -> Self {
+        if csz { Self::Bytes64 } else { Self::Bytes32 }
+    }
+
+    /// The number of bytes from the start of one context to the start of the next
+    pub const fn stride(self) -> usize {
+        match self {
+            Self::Bytes32 => 32,
+            Self::Bytes64 => 64,
+        }
+    }
+}
+
+/// xHCI 6.2.1 Device Context
+/// A Device Context has a Slot Context followed by 31 Endpoint Contexts.
+const DEVICE_CONTEXT_LEN: usize = 32;
+/// xHCI 6.2.5 Input Context
+/// An Input Context has an Input Control Context followed by a Device Context.
+const INPUT_CONTEXT_LEN: usize = 1 + DEVICE_CONTEXT_LEN;
+
+/// Contexts that are laid out back to back, [`ContextSize::stride`] bytes apart
+#[derive(Debug)]
+struct ContextArray {
+    mem: XhciAllocation,
+    context_size: ContextSize,
+    len: usize,
+}
+
+impl ContextArray {
+    fn new(
+        len: usize,
+        context_size: ContextSize,
+        align: NonZero<u64>,
+        boundary: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, AllocError> {
+        let size = len * context_size.stride();
+        let mem = XhciAllocation::new(
+            allocator,
+            AllocRequest {
+                size: NonZero::new(size as u64).ok_or(AllocError)?,
+                align,
+                boundary,
+                max_phys_addr,
+            },
+        )?;
+        // Software shall initialize the Input Context / Device Context to ‘0’
+        unsafe { mem.response().as_zeroed_slice::<u8>(size) };
+        Ok(Self {
+            mem,
+            context_size,
+            len,
+        })
+    }
+
+    fn get<T: FromBytes + IntoBytes + Immutable>(&self, index: usize) -> &T {
+        assert!(index < self.len);
+        // Every context is 32 bytes. With 64 byte contexts, the second half is reserved, so we never touch it.
+        let ptr =
+            (self.mem.response().virt_addr.get() + index * self.context_size.stride()) as *const T;
+        unsafe { &*ptr }
+    }
+
+    fn get_mut<T: FromBytes + IntoBytes + Immutable>(&mut self, index: usize) -> &mut T {
+        assert!(index < self.len);
+        let ptr =
+            (self.mem.response().virt_addr.get() + index * self.context_size.stride()) as *mut T;
+        unsafe { &mut *ptr }
+    }
+}
+
+/// Checks that `dci` is the Device Context Index of an endpoint (1 for the Default Control Endpoint, up to 31)
+fn endpoint_dci(dci: u8) -> Option<usize> {
+    (1..DEVICE_CONTEXT_LEN as u8)
+        .contains(&dci)
+        .then_some(dci as usize)
+}
+
+/// xHCI 6.2.1 Device Context (the Output Context that the xHC updates).
+/// Software should treat this as read-only after the DCBAA entry points to it.
+#[derive(Debug)]
+pub struct DeviceContext {
+    contexts: ContextArray,
+}
+
+impl DeviceContext {
+    pub(crate) fn new(
+        context_size: ContextSize,
+        page_size: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, AllocError> {
+        Ok(Self {
+            contexts: ContextArray::new(
+                DEVICE_CONTEXT_LEN,
+                context_size,
+                XHCI_DEVICE_CONTEXT_ALIGNMENT,
+                xhci_device_context_boundary(page_size),
+                max_phys_addr,
+                allocator,
+            )?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.contexts.mem.phys_addr()
+    }
+
+    pub fn slot(&self) -> &SlotContext {
+        self.contexts.get(0)
+    }
+
+    /// `dci` is the Device Context Index, which is 1 for the Default Control Endpoint.
+    /// Returns `None` if `dci` is not between 1 and 31.
+    pub fn endpoint(&self, dci: u8) -> Option<&EndpointContext> {
+        Some(self.contexts.get(endpoint_dci(dci)?))
+    }
+
+    /// # Safety
+    /// The DCBAA must not point to this Device Context anymore
+    pub(crate) unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe { self.contexts.mem.free(allocator) };
+    }
+}
+
+/// xHCI 6.2.5 Input Context, which software fills in and passes to commands such as Address Device, Configure Endpoint and Evaluate Context.
+#[derive(Debug)]
+pub struct InputContext {
+    contexts: ContextArray,
+}
+
+impl InputContext {
+    pub(crate) fn new(
+        context_size: ContextSize,
+        page_size: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, AllocError> {
+        Ok(Self {
+            contexts: ContextArray::new(
+                INPUT_CONTEXT_LEN,
+                context_size,
+                XHCI_INPUT_CONTROL_CONTEXT_ALIGNMENT,
+                xhci_input_control_context_boundary(page_size),
+                max_phys_addr,
+                allocator,
+            )?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.contexts.mem.phys_addr()
+    }
+
+    pub fn control(&self) -> &InputControlContext {
+        self.contexts.get(0)
+    }
+
+    pub fn control_mut(&mut self) -> &mut InputControlContext {
+        self.contexts.get_mut(0)
+    }
+
+    pub fn slot(&self) -> &SlotContext {
+        self.contexts.get(1)
+    }
+
+    pub fn slot_mut(&mut self) -> &mut SlotContext {
+        self.contexts.get_mut(1)
+    }
+
+    /// `dci` is the Device Context Index, which is 1 for the Default Control Endpoint.
+    /// Returns `None` if `dci` is not between 1 and 31.
+    pub fn endpoint(&self, dci: u8) -> Option<&EndpointContext> {
+        Some(self.contexts.get(1 + endpoint_dci(dci)?))
+    }
+
+    /// `dci` is the Device Context Index, which is 1 for the Default Control Endpoint.
+    /// Returns `None` if `dci` is not between 1 and 31.
+    pub fn endpoint_mut(&mut self, dci: u8) -> Option<&mut EndpointContext> {
+        Some(self.contexts.get_mut(1 + endpoint_dci(dci)?))
+    }
+
+    /// # Safety
+    /// The xHC must not be executing a command that uses this Input Context
+    pub(crate) unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe { self.contexts.mem.free(allocator) };
+    }
+}
+
+/// xHCI 6.2.2 Slot Context
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct SlotContext {
+    pub dw0: SlotContextDw0,
+    pub dw1: SlotContextDw1,
+    pub dw2: SlotContextDw2,
+    pub dw3: SlotContextDw3,
+    _reserved_0: [u32; 4],
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SlotContextDw0(u32);
+    impl Debug;
+
+    u32; pub route_string, set_route_string: 19, 0;
+    u8; pub speed, set_speed: 23, 20;
+    pub mtt, set_mtt: 25;
+    pub hub, set_hub: 26;
+    u8; pub context_entries, set_context_entries: 31, 27;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SlotContextDw1(u32);
+    impl Debug;
+
+    u16; pub max_exit_latency, set_max_exit_latency: 15, 0;
+    u8; pub root_hub_port_number, set_root_hub_port_number: 23, 16;
+    u8; pub number_of_ports, set_number_of_ports: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SlotContextDw2(u32);
+    impl Debug;
+
+    u8; pub parent_hub_slot_id, set_parent_hub_slot_id: 7, 0;
+    u8; pub parent_port_number, set_parent_port_number: 15, 8;
+    u8; pub ttt, set_ttt: 17, 16;
+    u16; pub interrupter_target, set_interrupter_target: 31, 22;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SlotContextDw3(u32);
+    impl Debug;
+
+    u8; pub usb_device_address, _: 7, 0;
+    u8; pub slot_state, _: 31, 27;
+}
+
+/// xHCI 6.2.3 Endpoint Context
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct EndpointContext {
+    pub dw0: EndpointContextDw0,
+    pub dw1: EndpointContextDw1,
+    pub tr_dequeue_pointer: TrDequeuePointer,
+    pub dw4: EndpointContextDw4,
+    _reserved_0: [u32; 3],
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct EndpointContextDw0(u32);
+    impl Debug;
+
+    u8; pub ep_state, _: 2, 0;
+    u8; pub mult, set_mult: 9, 8;
+    u8; pub max_p_streams, set_max_p_streams: 14, 10;
+    pub lsa, set_lsa: 15;
+    u8; pub interval, set_interval: 23, 16;
+    u8; pub max_esit_payload_hi, set_max_esit_payload_hi: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct EndpointContextDw1(u32);
+    impl Debug;
+
+    u8; pub cerr, set_cerr: 2, 1;
+    u8; pub ep_type, set_ep_type: 5, 3;
+    pub hid, set_hid: 7;
+    u8; pub max_burst_size, set_max_burst_size: 15, 8;
+    u16; pub max_packet_size, set_max_packet_size: 31, 16;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct TrDequeuePointer(u64);
+    impl Debug;
+
+    pub dcs, set_dcs: 0;
+    u64; _tr_dequeue_pointer, _set_tr_dequeue_pointer: 63, 4;
+}
+
+impl TrDequeuePointer {
+    pub fn tr_dequeue_pointer(&self) -> u64 {
+        self._tr_dequeue_pointer() << 4
+    }
+
+    pub fn set_tr_dequeue_pointer(&mut self, tr_dequeue_pointer: u64) {
+        self._set_tr_dequeue_pointer(tr_dequeue_pointer >> 4);
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct EndpointContextDw4(u32);
+    impl Debug;
+
+    u16; pub average_trb_length, set_average_trb_length: 15, 0;
+    u16; pub max_esit_payload_lo, set_max_esit_payload_lo: 31, 16;
+}
+
+/// xHCI 6.2.5.1 Input Control Context
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct InputControlContext {
+    /// Bit n tells the xHC to drop the context with Device Context Index n. Bits 0 and 1 are reserved.
+    pub drop_context_flags: u32,
+    /// Bit n tells the xHC to add (or evaluate) the context with Device Context Index n. Bit 0 is the Slot Context.
+    pub add_context_flags: u32,
+    _reserved_0: [u32; 5],
+    pub dw7: InputControlContextDw7,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct InputControlContextDw7(u32);
+    impl Debug;
+
+    u8; pub configuration_value, set_configuration_value: 7, 0;
+    u8; pub interface_number, set_interface_number: 15, 8;
+    u8; pub alternate_setting, set_alternate_setting: 23, 16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: NonZero<u64> = NonZero::new(4096).unwrap();
+
+    /// The byte offset of a context from the start of the Device Context or Input Context
+    fn offset<T>(context: &T, phys_addr: u64) -> usize {
+        // The test allocator's physical addresses are the virtual addresses
+        context as *const T as usize - phys_addr as usize
+    }
+
+    #[test]
+    fn contexts_are_32_bytes() {
+        assert_eq!(size_of::<SlotContext>(), 32);
+        assert_eq!(size_of::<EndpointContext>(), 32);
+        assert_eq!(size_of::<InputControlContext>(), 32);
+    }
+
+    #[test]
+    fn device_context_offsets() {
+        for (context_size, stride) in [(ContextSize::Bytes32, 32), (ContextSize::Bytes64, 64)] {
+            let device_context =
+                DeviceContext::new(context_size, PAGE_SIZE, u64::MAX, &mut TestAllocator).unwrap();
+            let phys_addr = device_context.phys_addr();
+            assert_eq!(offset(device_context.slot(), phys_addr), 0);
+            assert_eq!(
+                offset(device_context.endpoint(1).unwrap(), phys_addr),
+                stride
+            );
+            assert_eq!(
+                offset(device_context.endpoint(31).unwrap(), phys_addr),
+                31 * stride
+            );
+            assert!(device_context.endpoint(0).is_none());
+            assert!(device_context.endpoint(32).is_none());
+            unsafe { device_context.free(&mut TestAllocator) };
+        }
+    }
+
+    #[test]
+    fn input_context_offsets() {
+        for (context_size, stride) in [(ContextSize::Bytes32, 32), (ContextSize::Bytes64, 64)] {
+            let mut input_context =
+                InputContext::new(context_size, PAGE_SIZE, u64::MAX, &mut TestAllocator).unwrap();
+            let phys_addr = input_context.phys_addr();
+            // The Input Control Context comes first, so everything else is one context later than in a Device Context
+            assert_eq!(offset(input_context.control(), phys_addr), 0);
+            assert_eq!(offset(input_context.slot(), phys_addr), stride);
+            assert_eq!(offset(input_context.slot_mut(), phys_addr), stride);
+            assert_eq!(
+                offset(input_context.endpoint(1).unwrap(), phys_addr),
+                2 * stride
+            );
+            assert_eq!(
+                offset(input_context.endpoint_mut(31).unwrap(), phys_addr),
+                32 * stride
+            );
+            assert!(input_context.endpoint(0).is_none());
+            assert!(input_context.endpoint_mut(32).is_none());
+            unsafe { input_context.free(&mut TestAllocator) };
+        }
+    }
+}
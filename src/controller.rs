@@ -0,0 +1,918 @@
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec, vec::Vec};
+use core::{marker::PhantomData, num::NonZero};
+
+use volatile::VolatileRef;
+
+use crate::*;
+
+/// The xHC is halted (HCH = 1) and was just reset, so none of its registers point to any memory.
+pub struct Halted;
+
+/// The xHC is still halted, but DCBAAP, CRCR and the event ring registers point to memory that the controller owns.
+/// This is where you can set up MSI-X before interrupts get enabled.
+pub struct Configured;
+
+/// Run/Stop (R/S) is set and the xHC accepts doorbells.
+pub struct Running;
+
+/// The xHC is halted and it saved its internal state (xHCI 4.23.2), so the system can remove its power.
+pub struct Suspended;
+
+/// An error that made the xHC stop by itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatalError {
+    /// xHCI 4.10.2.6 Host System Error (HSE)
+    /// Something went wrong between the xHC and the rest of the system, like a PCI error while accessing memory.
+    HostSystemError,
+    /// xHCI 4.24.1 Internal Errors (HCE)
+    /// The xHC found a problem with itself or with the data structures we gave it.
+    HostControllerError,
+}
+
+/// What [`Controller::restore_state`] had to do to get the xHC working again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeOutcome {
+    /// The xHC restored its internal state, so device slots and endpoints are the same as before suspending
+    Restored,
+    /// The xHC couldn't save or restore its state (Save/Restore Error), so it was reset and configured again.
+    /// Every device slot was disabled, so every device has to be enumerated again.
+    /// These are the slot IDs of the devices that were lost. Free their Device Contexts and transfer rings, like after [`Driver::recover`].
+    Reinitialized(Vec<u8>),
+}
+
+/// An xHC in one of the stages of xHCI 4.2 Host Controller Initialization.
+/// Every stage transition is a method that consumes the controller, so the stages can't be skipped,
+/// and things like ringing doorbells are only possible on a [`Controller<Running>`].
+///
+/// [`Driver::new`] goes through all of the stages for you.
+/// If you need to do something in between (like MSI-X setup or controller specific quirks),
+/// go through the stages yourself and then use [`Driver::from_controller`].
+pub struct Controller<'a, S> {
+    pub(crate) mmio: XhciMmio,
+    pub(crate) capability_regs: VolatileRef<'a, CapabilityRegs>,
+    pub(crate) operational_regs: VolatileRef<'a, OperationalRegs>,
+    pub(crate) port_regs: VolatileRef<'a, PortRegsArray>,
+    pub(crate) runtime_regs: VolatileRef<'a, RuntimeRegisters>,
+    doorbell_regs: VolatileRef<'a, DoorbellArray>,
+    /// Only `Some` on a [`Suspended`] controller
+    saved_registers: Option<SavedRegisters>,
+    /// Only `None` on a [`Halted`] controller.
+    /// It's boxed so that moving the controller between stages stays cheap.
+    pub(crate) memory: Option<Box<ControllerMemory<'a>>>,
+    state: PhantomData<S>,
+}
+
+/// Everything that the xHC's registers point to.
+/// It's not freed on drop because it can only be freed after the xHC is halted.
+pub(crate) struct ControllerMemory<'a> {
+    pub page_size: NonZero<u64>,
+    pub context_size: ContextSize,
+    pub max_phys_addr: u64,
+    pub max_slots_en: u8,
+    /// One for each interrupter, indexed by the interrupter number
+    pub moderation: Vec<Moderation>,
+    /// Interrupts are never enabled (see [`DriverConfig::polling_mode`])
+    pub polling_mode: bool,
+    pub dcbaa: Dcbaa<'a>,
+    pub scratchpad_buffers: Option<ScratchpadBuffers>,
+    pub command_ring: CommandRing2<'a>,
+    /// One for each interrupter, indexed by the interrupter number
+    pub event_rings: Vec<EventRing2<'a>>,
+    /// Keyed by Slot ID and Device Context Index
+    pub transfer_rings: BTreeMap<(u8, u8), TransferRing<'a>>,
+    /// Port resets from [`Driver::reset_port_async`], keyed by port number
+    pub port_resets: BTreeMap<u8, Completer<()>>,
+}
+
+impl ControllerMemory<'_> {
+    /// Throws away everything the xHC was doing, since a reset disables every device slot and forgets the ring positions
+    fn reinitialize(&mut self) {
+        self.dcbaa.clear_device_contexts();
+        self.command_ring.reinitialize();
+        for event_ring in &mut self.event_rings {
+            event_ring.reinitialize();
+        }
+        // The transfer rings stay allocated until they're freed, but their endpoints are gone
+        for transfer_ring in self.transfer_rings.values_mut() {
+            transfer_ring.reinitialize();
+        }
+        // A reset resets the ports too
+        self.port_resets.clear();
+    }
+}
+
+/// xHCI 4.23.2 Save and Restore State
+/// The registers that software has to save before setting CSS, and write back before setting CRS.
+/// CRCR isn't here because its Command Ring Pointer always reads as 0. We get it from the command ring instead.
+#[derive(Debug, Clone)]
+struct SavedRegisters {
+    /// The xHC finished saving its internal state without a Save/Restore Error
+    state_saved: bool,
+    dn_ctrl: DnCtrl,
+    config: ConfigureRegister,
+    dcbaap: Dcbaap,
+    /// One for each interrupter that we set up
+    interrupters: Vec<SavedInterrupterRegisters>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SavedInterrupterRegisters {
+    iman: Iman,
+    imod: Imod,
+    erstsz: Erstsz,
+    erstba: Erstba,
+    erdp: Erdp,
+}
+
+impl ControllerMemory<'_> {
+    /// # Safety
+    /// The xHC must be halted
+    unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe {
+            for transfer_ring in self.transfer_rings.into_values() {
+                transfer_ring.free(allocator);
+            }
+            for event_ring in self.event_rings {
+                event_ring.free(allocator);
+            }
+            self.command_ring.free(allocator);
+            if let Some(scratchpad_buffers) = self.scratchpad_buffers {
+                scratchpad_buffers.free(allocator);
+            }
+            self.dcbaa.free(allocator);
+        }
+    }
+}
+
+impl<'a, S> Controller<'a, S> {
+    fn into_state<T>(self) -> Controller<'a, T> {
+        Controller {
+            mmio: self.mmio,
+            capability_regs: self.capability_regs,
+            operational_regs: self.operational_regs,
+            port_regs: self.port_regs,
+            runtime_regs: self.runtime_regs,
+            doorbell_regs: self.doorbell_regs,
+            saved_registers: self.saved_registers,
+            memory: self.memory,
+            state: PhantomData,
+        }
+    }
+
+    fn memory(&self) -> &ControllerMemory<'a> {
+        self.memory
+            .as_ref()
+            .expect("only a halted controller has no memory")
+    }
+
+    /// Stops the xHC if it's running and resets it (HCRST).
+    /// Afterwards none of its registers point to our memory anymore.
+    fn reset_registers(&mut self, clock: &mut impl XhciClock) -> Result<(), InitError> {
+        let operational_regs = &mut self.operational_regs;
+
+        // Software shall not write any Doorbell or Operational register of the xHC, other than the USBSTS register, until CNR = ‘0’.
+        poll_until(clock, CONTROLLER_NOT_READY_TIMEOUT, || {
+            !operational_regs
+                .as_ptr()
+                .usb_sts()
+                .read()
+                .controller_not_ready()
+        })
+        .map_err(|Timeout| InitError::ControllerNotReady)?;
+
+        // Software shall not set HCRST to ‘1’ when the HCHalted (HCH) bit in the USBSTS register is a ‘0’.
+        // The firmware (or a previous kernel) could have left the controller running, so stop it first.
+        if !operational_regs.as_ptr().usb_sts().read().hc_halted() {
+            operational_regs
+                .as_mut_ptr()
+                .usb_cmd()
+                .update(|mut usb_cmd| {
+                    usb_cmd.set_run_stop(false);
+                    usb_cmd
+                });
+            poll_until(clock, HALT_TIMEOUT, || {
+                operational_regs.as_ptr().usb_sts().read().hc_halted()
+            })
+            .map_err(|Timeout| InitError::HaltTimeout)?;
+        }
+
+        operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_host_controller_reset(true);
+                usb_cmd
+            });
+        // Wait until reset is done
+        poll_until(clock, RESET_TIMEOUT, || {
+            !operational_regs
+                .as_ptr()
+                .usb_cmd()
+                .read()
+                .host_controller_reset()
+        })
+        .map_err(|Timeout| InitError::ResetTimeout)?;
+        poll_until(clock, CONTROLLER_NOT_READY_TIMEOUT, || {
+            !operational_regs
+                .as_ptr()
+                .usb_sts()
+                .read()
+                .controller_not_ready()
+        })
+        .map_err(|Timeout| InitError::ControllerNotReady)?;
+        if self.host_controller_error() {
+            return Err(InitError::HostControllerError);
+        }
+        Ok(())
+    }
+
+    /// Points the xHC's registers to the memory from [`Controller::configure`].
+    /// The xHC must be halted, and this has to be done again whenever it's reset.
+    fn program_registers(&mut self) {
+        let memory = self
+            .memory
+            .as_ref()
+            .expect("only a halted controller has no memory");
+        let operational_regs = &mut self.operational_regs;
+
+        // xHCI 4 Operational Model
+        // xHCI 4.2 Host Controller Initialization
+        // Program the Max Device Slots Enabled (MaxSlotsEn) field in the CONFIG register (5.4.7) to enable the device slots that system software is going to use.
+        operational_regs.as_mut_ptr().config().update(|mut config| {
+            config.set_max_slots_en(memory.max_slots_en);
+            config
+        });
+
+        // Program the Device Context Base Address Array Pointer (DCBAAP) register (5.4.6) with a 64-bit address pointing to where the Device Context Base Address Array is located.
+        operational_regs.as_mut_ptr().dcbaap().update(|mut dcbaap| {
+            dcbaap.set_dcbaap(memory.dcbaa.phys_addr());
+            dcbaap
+        });
+
+        // Define the Command Ring Dequeue Pointer by programming the Command Ring Control Register (5.4.5) with a 64-bit address pointing to the starting address of the first TRB of the Command Ring.
+        memory
+            .command_ring
+            .update_crcr(operational_regs.as_mut_ptr().crcr());
+
+        // Initialize each active interrupter
+        for (interrupter, event_ring) in memory.event_rings.iter().enumerate() {
+            let interrupter_regs = self
+                .runtime_regs
+                .as_mut_ptr()
+                .interrupter_register_sets()
+                .as_slice()
+                .index(interrupter);
+
+            // Program the Interrupter Event Ring Segment Table Size (ERSTSZ) register (5.5.2.3.1) with the number of segments described by the Event Ring Segment Table.
+            interrupter_regs.erstsz().update(|mut erstsz| {
+                erstsz.set_erstsz(event_ring.erst_len());
+                erstsz
+            });
+
+            // Program the Interrupter Event Ring Dequeue Pointer (ERDP) register (5.5.2.3.3) with the starting address of the first segment described by the Event Ring Segment Table.
+            event_ring.update_erdp(interrupter_regs.erdp());
+
+            // Program the Interrupter Event Ring Segment Table Base Address (ERSTBA) register (5.5.2.3.2) with a 64-bit address pointer to where the Event Ring Segment Table is located.
+            // Note that writing the ERSTBA enables the Event Ring. Refer to section 4.9.4 for more information on the Event Ring registers and their initialization.
+            interrupter_regs.erstba().update(|mut erstba| {
+                erstba.set_erstba(event_ring.erst_phys_addr());
+                erstba
+            });
+
+            // Initializing the Interval field of the Interrupt Moderation register (5.5.2.2) with the target interrupt moderation rate.
+            // By default this is 0x0, which will not throttle interrupts at all
+            interrupter_regs.imod().update(|mut imod| {
+                imod.set_imodi(memory.moderation[interrupter].imodi);
+                imod
+            });
+        }
+    }
+
+    /// Host Controller Error (HCE) in USBSTS
+    pub fn host_controller_error(&self) -> bool {
+        self.operational_regs.as_ptr().usb_sts().read().hce()
+    }
+
+    /// Checks USBSTS for errors that stop the xHC.
+    /// Once one of these happens, the xHC has to be reset before it does anything again.
+    pub fn fatal_error(&self) -> Option<FatalError> {
+        let usb_sts = self.operational_regs.as_ptr().usb_sts().read();
+        if usb_sts.hce() {
+            Some(FatalError::HostControllerError)
+        } else if usb_sts.hse() {
+            Some(FatalError::HostSystemError)
+        } else {
+            None
+        }
+    }
+
+    /// Resets the xHC and sets it up again with the memory it already has, like it was just configured.
+    /// Returns the slot IDs that had a Device Context, since every device slot is disabled by the reset.
+    fn reinitialize_inner(&mut self, clock: &mut impl XhciClock) -> Result<Vec<u8>, InitError> {
+        self.reset_registers(clock)?;
+        let memory = self
+            .memory
+            .as_mut()
+            .expect("only a halted controller has no memory");
+        let lost_slots = memory.dcbaa.slots_in_use().collect();
+        memory.reinitialize();
+        self.program_registers();
+        log::debug!("xHCI - Reinitialized host controller");
+        Ok(lost_slots)
+    }
+}
+
+impl<'a> Controller<'a, Halted> {
+    /// Stops the xHC if it's running and resets it (HCRST).
+    ///
+    /// If the BIOS could be using the xHC, call [`XhciMmio::take_ownership_from_bios`] before this.
+    pub fn reset(mmio: XhciMmio, clock: &mut impl XhciClock) -> Result<Self, InitError> {
+        let capability_regs = mmio.registers::<CapabilityRegs>(0)?;
+        log::debug!(
+            "Capability registers: {:#X?}",
+            capability_regs.as_ptr().read()
+        );
+        let cap_length = capability_regs.as_ptr().cap_length().read() as usize;
+        let operational_regs = mmio.registers::<OperationalRegs>(cap_length)?;
+        let port_regs = mmio.registers::<PortRegsArray>(cap_length + PORT_REGS_OFFSET)?;
+        let runtime_regs =
+            mmio.registers::<RuntimeRegisters>(capability_regs.as_ptr().rts_off().read() as usize)?;
+        let doorbell_regs = mmio.registers::<DoorbellArray>(
+            capability_regs.as_ptr().doorbell_offset().read() as usize,
+        )?;
+
+        let hcs_params_1 = capability_regs.as_ptr().hcs_params_1().read();
+        if hcs_params_1.max_slots() == 0 {
+            Err(UnsupportedConfiguration::NoDeviceSlots)?;
+        }
+        if hcs_params_1.max_interrupters() == 0 {
+            Err(UnsupportedConfiguration::NoInterrupters)?;
+        }
+
+        let mut controller = Self {
+            mmio,
+            capability_regs,
+            operational_regs,
+            port_regs,
+            runtime_regs,
+            doorbell_regs,
+            saved_registers: None,
+            memory: None,
+            state: PhantomData,
+        };
+        controller.reset_registers(clock)?;
+        log::debug!("xHCI - Reset host controller");
+        Ok(controller)
+    }
+
+    /// Allocates the DCBAA, scratchpad buffers, command ring and event rings and points the xHC's registers to them.
+    /// Interrupts are not enabled yet, that's done in [`Controller::start`].
+    ///
+    /// If this fails, the memory that was already allocated is freed.
+    pub fn configure(
+        self,
+        config: DriverConfig,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Controller<'a, Configured>, InitError> {
+        let capability_regs = &self.capability_regs;
+
+        // xHCI 5.4.3 Page Size Register (PAGESIZE)
+        // Scratchpad buffers have to be exactly this size, and many structures can't cross a page boundary
+        let page_size = self
+            .operational_regs
+            .as_ptr()
+            .page_size()
+            .read()
+            .page_size_bytes()
+            .ok_or(UnsupportedConfiguration::InvalidPageSize)?;
+        log::debug!("xHCI - Page size: {page_size:#X}");
+
+        let config = config.validate(
+            capability_regs.as_ptr().hcs_params_1().read(),
+            capability_regs.as_ptr().hcs_params_2().read(),
+            page_size,
+        )?;
+        let max_slots = config.max_slots_en;
+
+        // xHCI 5.3.6 Capability Parameters 1 (HCCPARAMS1)
+        // If CSZ is set, every context structure takes up 64 bytes instead of 32
+        let context_size =
+            ContextSize::from_csz(capability_regs.as_ptr().hcc_params_1().read().csz());
+        log::debug!("xHCI - Context size: {context_size:?}");
+
+        // Every pointer we give the xHC has to fit in the address width it supports
+        let max_phys_addr = capability_regs
+            .as_ptr()
+            .hcc_params_1()
+            .read()
+            .max_phys_addr();
+        log::debug!("xHCI - Max physical address: {max_phys_addr:#X}");
+
+        // 6.1 Device Context Base Address Array
+        let mut dcbaa = Dcbaa::new(max_slots, page_size, max_phys_addr, allocator)?;
+
+        // If the Max Scratchpad Buffers field of the HCSPARAMS2 register is > ‘0’, then the first entry (entry_0) in the DCBAA shall contain a pointer to the Scratchpad Buffer Array.
+        // If the Max Scratchpad Buffers field of the HCSPARAMS2 register is = ‘0’, then the first entry (entry_0) in the DCBAA is reserved and shall be cleared to ‘0’ by software.
+        // xHCI 4.20 Scratchpad Buffers
+        // 1. Software examines the Max Scratchpad Buffers Hi and Lo fields in the HCSPARAMS2 register.
+        let scratchpad_buffers = NonZero::new(
+            capability_regs
+                .as_ptr()
+                .hcs_params_2()
+                .read()
+                .max_scratchpad_buffers(),
+        )
+        .map(|max_scratchpad_buffers| {
+            ScratchpadBuffers::new(
+                max_scratchpad_buffers,
+                page_size,
+                max_phys_addr,
+                &mut *allocator,
+            )
+        })
+        .transpose();
+        // The xHC doesn't know about any of this memory yet, so on errors we can give back everything allocated so far
+        let scratchpad_buffers = match scratchpad_buffers {
+            Ok(scratchpad_buffers) => scratchpad_buffers,
+            Err(e) => {
+                unsafe { dcbaa.free(allocator) };
+                return Err(e);
+            }
+        };
+        if let Some(scratchpad_buffers) = &scratchpad_buffers {
+            // 3. Software writes the base address of the Scratchpad Buffer Array to the DCBAA (Slot 0) entry.
+            dcbaa.set(0, scratchpad_buffers.phys_addr());
+        }
+
+        let command_ring = match CommandRing2::new(
+            config.command_ring_len,
+            config.command_ring_segments,
+            config.command_overflow_queue_len,
+            max_phys_addr,
+            &mut *allocator,
+        ) {
+            Ok(command_ring) => command_ring,
+            Err(e) => {
+                unsafe {
+                    if let Some(scratchpad_buffers) = scratchpad_buffers {
+                        scratchpad_buffers.free(allocator);
+                    }
+                    dcbaa.free(allocator);
+                }
+                return Err(e);
+            }
+        };
+
+        // Initialize each active interrupter by:
+        // Defining the Event Ring: (refer to section 4.9.4 for a discussion of Event Ring Management.)
+        // Software maintains an Event Ring Consumer Cycle State (CCS) bit, initializing it to ‘1’ and toggling it every time the Event Ring Dequeue Pointer wraps back to the beginning of the Event Ring.
+
+        // Every interrupter has its own event ring. Each event ring allocates its segments and the Event Ring Segment Table (ERST) that points to them.
+        let mut event_rings = Vec::with_capacity(config.interrupters.into());
+        for _ in 0..config.interrupters {
+            match EventRing2::new(
+                config.event_ring_segment_len,
+                config.event_ring_segments,
+                page_size,
+                max_phys_addr,
+                &mut *allocator,
+            ) {
+                Ok(event_ring) => event_rings.push(event_ring),
+                Err(e) => {
+                    unsafe {
+                        for event_ring in event_rings {
+                            event_ring.free(allocator);
+                        }
+                        command_ring.free(allocator);
+                        if let Some(scratchpad_buffers) = scratchpad_buffers {
+                            scratchpad_buffers.free(allocator);
+                        }
+                        dcbaa.free(allocator);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let memory = Box::new(ControllerMemory {
+            page_size,
+            context_size,
+            max_phys_addr,
+            max_slots_en: max_slots,
+            moderation: vec![Moderation::fixed(config.imodi); config.interrupters.into()],
+            polling_mode: config.polling_mode,
+            dcbaa,
+            scratchpad_buffers,
+            command_ring,
+            event_rings,
+            transfer_rings: BTreeMap::new(),
+            port_resets: BTreeMap::new(),
+        });
+        let mut controller = Controller {
+            memory: Some(memory),
+            ..self
+        };
+        controller.program_registers();
+        Ok(controller.into_state())
+    }
+}
+
+impl<'a> Controller<'a, Configured> {
+    /// The page size reported by the xHC's PAGESIZE register
+    pub fn page_size(&self) -> NonZero<u64> {
+        self.memory().page_size
+    }
+
+    /// Whether the xHC uses 32 or 64 byte contexts (HCCPARAMS1.CSZ)
+    pub fn context_size(&self) -> ContextSize {
+        self.memory().context_size
+    }
+
+    /// The highest physical address the xHC can access (from HCCPARAMS1.AC64)
+    pub fn max_phys_addr(&self) -> u64 {
+        self.memory().max_phys_addr
+    }
+
+    /// Enables interrupts (unless [`DriverConfig::polling_mode`] is on) and sets Run/Stop (R/S).
+    ///
+    /// If the xHC doesn't start, the controller is returned with the error so that it can still be reset (and its memory freed).
+    pub fn start(
+        mut self,
+        clock: &mut impl XhciClock,
+    ) -> Result<Controller<'a, Running>, (Self, InitError)> {
+        // Defining the interrupts:
+        // Enable the MSI-X interrupt mechanism by setting the MSI-X Enable flag in the MSI-X Capability Structure Message Control register (5.2.8.3).
+        // It's not this driver's job to do this. Do it before calling this function.
+        // From my experience, QEMU can do either legacy PCI interrupts or MSI-X interrupts. Both work.
+        // In theory on real hardware it must support MSI, MSI-X or both. Legacy interrupts may or may not work.
+
+        // In polling mode the xHC still writes events and sets IP and EINT, it just never signals an interrupt
+        if !self.memory().polling_mode {
+            // Enable system bus interrupt generation by writing a ‘1’ to the Interrupter Enable (INTE) flag of the USBCMD register (5.4.1).
+            self.operational_regs
+                .as_mut_ptr()
+                .usb_cmd()
+                .update(|mut usb_cmd| {
+                    usb_cmd.set_interrupter_enable(true);
+                    usb_cmd
+                });
+
+            // Enable the Interrupter by writing a ‘1’ to the Interrupt Enable (IE) field of the Interrupter Management register (5.5.2.1).
+            for interrupter in 0..self.memory().event_rings.len() {
+                self.runtime_regs
+                    .as_mut_ptr()
+                    .interrupter_register_sets()
+                    .as_slice()
+                    .index(interrupter)
+                    .iman()
+                    .update(|mut iman| {
+                        iman.set_interrupt_enable(true);
+                        iman
+                    });
+            }
+        }
+
+        // Write the USBCMD (5.4.1) to turn the host controller ON via setting the Run/Stop (R/S) bit to ‘1’. This operation allows the xHC to begin accepting doorbell references.
+        self.operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_run_stop(true);
+                usb_cmd
+            });
+        let operational_regs = &self.operational_regs;
+        if poll_until(clock, START_TIMEOUT, || {
+            !operational_regs.as_ptr().usb_sts().read().hc_halted()
+        })
+        .is_err()
+        {
+            return Err((self, InitError::StartTimeout));
+        }
+        if self.host_controller_error() {
+            return Err((self, InitError::HostControllerError));
+        }
+        Ok(self.into_state())
+    }
+
+    /// Resets the xHC and sets it up again with the same rings and DCBAA, which are cleared.
+    /// This is how to recover from a [`FatalError`] (after [`Controller::halt`]).
+    ///
+    /// Returns the slot IDs that had a Device Context. Those devices are gone, so treat them as disconnected.
+    /// Their Device Contexts are no longer in the DCBAA, so they can be freed.
+    pub fn reinitialize(&mut self, clock: &mut impl XhciClock) -> Result<Vec<u8>, InitError> {
+        self.reinitialize_inner(clock)
+    }
+
+    /// xHCI 4.23.2 Save and Restore State
+    /// Saves the registers that point to our memory and asks the xHC to save its internal state (CSS),
+    /// so that it can be restored with [`Controller::restore_state`] after the system removes its power (like in S3).
+    ///
+    /// If the xHC fails to save its state, this still succeeds (with a warning),
+    /// and [`Controller::restore_state`] will reinitialize the xHC instead.
+    pub fn save_state(mut self, clock: &mut impl XhciClock) -> Controller<'a, Suspended> {
+        let interrupters = (0..self.memory().event_rings.len())
+            .map(|interrupter| {
+                let interrupter_regs = self
+                    .runtime_regs
+                    .as_ptr()
+                    .interrupter_register_sets()
+                    .as_slice()
+                    .index(interrupter);
+                SavedInterrupterRegisters {
+                    iman: interrupter_regs.iman().read(),
+                    imod: interrupter_regs.imod().read(),
+                    erstsz: interrupter_regs.erstsz().read(),
+                    erstba: interrupter_regs.erstba().read(),
+                    erdp: interrupter_regs.erdp().read(),
+                }
+            })
+            .collect();
+        let operational_regs = &mut self.operational_regs;
+        // Software shall save any state needed to restore the Operational and Runtime registers
+        let mut saved_registers = SavedRegisters {
+            state_saved: false,
+            dn_ctrl: operational_regs.as_ptr().dn_ctrl().read(),
+            config: operational_regs.as_ptr().config().read(),
+            dcbaap: operational_regs.as_ptr().dcbaap().read(),
+            interrupters,
+        };
+
+        // SRE is RW1C, and we only care about errors from this save
+        operational_regs.as_mut_ptr().usb_sts().update(|usb_sts| {
+            let mut new_usb_sts = usb_sts.to_neutral();
+            new_usb_sts.set_sre(true);
+            new_usb_sts
+        });
+        // Set the Controller Save State (CSS) flag in the USBCMD register (5.4.1) and wait for the Save State Status (SSS) flag in the USBSTS register (5.4.2) to transition to ‘0’.
+        operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_css(true);
+                usb_cmd
+            });
+        let operational_regs = &self.operational_regs;
+        match poll_until(clock, SAVE_STATE_TIMEOUT, || {
+            !operational_regs.as_ptr().usb_sts().read().sss()
+        }) {
+            Ok(()) if operational_regs.as_ptr().usb_sts().read().sre() => {
+                log::warn!("xHCI - Save/Restore Error while saving state")
+            }
+            Ok(()) => saved_registers.state_saved = true,
+            Err(Timeout) => log::warn!("xHCI - Timed out saving state"),
+        }
+
+        Controller {
+            saved_registers: Some(saved_registers),
+            ..self
+        }
+        .into_state()
+    }
+
+    /// Disables interrupts, resets the xHC and frees the memory from [`Controller::configure`].
+    ///
+    /// The memory is freed even if the reset doesn't finish, since the xHC is already halted.
+    pub fn reset_and_free(
+        mut self,
+        allocator: &mut impl XhciMemAllocator,
+        clock: &mut impl XhciClock,
+    ) -> Result<Controller<'a, Halted>, InitError> {
+        self.operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_interrupter_enable(false);
+                usb_cmd
+            });
+        // Disable the interrupters and clear any interrupt that was pending (IP is RW1C)
+        for interrupter in 0..self.memory().event_rings.len() {
+            self.runtime_regs
+                .as_mut_ptr()
+                .interrupter_register_sets()
+                .as_slice()
+                .index(interrupter)
+                .iman()
+                .update(|mut iman| {
+                    iman.set_interrupt_enable(false);
+                    iman.set_interrupt_pending(true);
+                    iman
+                });
+        }
+
+        // Resetting sets DCBAAP, CRCR, ERSTBA and every other register that points to our memory back to 0
+        let memory = self.memory.take();
+        let mut controller = self.into_state::<Halted>();
+        let reset_result = controller.reset_registers(clock);
+        // The xHC is halted, so it won't access any of our memory even if the reset didn't finish
+        if let Some(memory) = memory {
+            unsafe { (*memory).free(allocator) };
+        }
+        reset_result.map(|()| controller)
+    }
+}
+
+impl<'a> Controller<'a, Suspended> {
+    /// xHCI 4.23.2 Save and Restore State
+    /// Writes back the registers that were saved by [`Controller::save_state`] and asks the xHC to restore its internal state (CRS).
+    /// If that doesn't work, the xHC is reset and configured again with the same memory, and every device slot is lost.
+    ///
+    /// Afterwards the xHC is still halted, so use [`Controller::start`].
+    /// If the xHC can't be reset, the controller is returned with the error so that its memory can still be freed.
+    pub fn restore_state(
+        mut self,
+        clock: &mut impl XhciClock,
+    ) -> Result<(Controller<'a, Configured>, ResumeOutcome), (Self, InitError)> {
+        let saved_registers = self
+            .saved_registers
+            .clone()
+            .expect("a suspended controller always has saved registers");
+
+        // After power comes back the xHC could still be initializing itself
+        let operational_regs = &self.operational_regs;
+        if poll_until(clock, CONTROLLER_NOT_READY_TIMEOUT, || {
+            !operational_regs
+                .as_ptr()
+                .usb_sts()
+                .read()
+                .controller_not_ready()
+        })
+        .is_err()
+        {
+            return Err((self, InitError::ControllerNotReady));
+        }
+
+        if saved_registers.state_saved {
+            if self.restore_registers(saved_registers, clock) {
+                return Ok((self.into_state(), ResumeOutcome::Restored));
+            }
+        } else {
+            log::warn!("xHCI - State was not saved, reinitializing");
+        }
+
+        // If the xHC could not restore its state (SRE = 1), software shall reinitialize the xHC as described in section 4.2
+        match self.reinitialize_inner(clock) {
+            Ok(lost_slots) => Ok((self.into_state(), ResumeOutcome::Reinitialized(lost_slots))),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Returns `true` if the xHC restored its state
+    fn restore_registers(
+        &mut self,
+        saved_registers: SavedRegisters,
+        clock: &mut impl XhciClock,
+    ) -> bool {
+        let memory = self
+            .memory
+            .as_ref()
+            .expect("a suspended controller always has memory");
+        let operational_regs = &mut self.operational_regs;
+
+        // Restore the Operational and Runtime registers with their previously saved state
+        operational_regs
+            .as_mut_ptr()
+            .dn_ctrl()
+            .write(saved_registers.dn_ctrl);
+        operational_regs
+            .as_mut_ptr()
+            .config()
+            .write(saved_registers.config);
+        operational_regs
+            .as_mut_ptr()
+            .dcbaap()
+            .write(saved_registers.dcbaap);
+        memory
+            .command_ring
+            .update_crcr(operational_regs.as_mut_ptr().crcr());
+        for (interrupter, saved_interrupter_registers) in
+            saved_registers.interrupters.iter().enumerate()
+        {
+            let interrupter_regs = self
+                .runtime_regs
+                .as_mut_ptr()
+                .interrupter_register_sets()
+                .as_slice()
+                .index(interrupter);
+            interrupter_regs
+                .erstsz()
+                .write(saved_interrupter_registers.erstsz);
+            interrupter_regs
+                .erstba()
+                .write(saved_interrupter_registers.erstba);
+            interrupter_regs
+                .erdp()
+                .write(saved_interrupter_registers.erdp);
+            interrupter_regs
+                .iman()
+                .write(saved_interrupter_registers.iman);
+            interrupter_regs
+                .imod()
+                .write(saved_interrupter_registers.imod);
+        }
+
+        // Set the Controller Restore State (CRS) flag in the USBCMD register (5.4.1) to ‘1’ and wait for the Restore State Status (RSS) in the USBSTS register (5.4.2) to transition to ‘0’.
+        operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_crs(true);
+                usb_cmd
+            });
+        let operational_regs = &self.operational_regs;
+        match poll_until(clock, RESTORE_STATE_TIMEOUT, || {
+            !operational_regs.as_ptr().usb_sts().read().rss()
+        }) {
+            Ok(()) if operational_regs.as_ptr().usb_sts().read().sre() => {
+                log::warn!("xHCI - Save/Restore Error while restoring state, reinitializing");
+                false
+            }
+            Ok(()) => true,
+            Err(Timeout) => {
+                log::warn!("xHCI - Timed out restoring state, reinitializing");
+                false
+            }
+        }
+    }
+
+    /// Resets the xHC and frees the memory, for when it won't be resumed
+    pub fn reset_and_free(
+        self,
+        allocator: &mut impl XhciMemAllocator,
+        clock: &mut impl XhciClock,
+    ) -> Result<Controller<'a, Halted>, InitError> {
+        Controller {
+            saved_registers: None,
+            ..self
+        }
+        .into_state::<Configured>()
+        .reset_and_free(allocator, clock)
+    }
+}
+
+impl<'a> Controller<'a, Running> {
+    /// The page size reported by the xHC's PAGESIZE register
+    pub fn page_size(&self) -> NonZero<u64> {
+        self.memory().page_size
+    }
+
+    /// Whether the xHC uses 32 or 64 byte contexts (HCCPARAMS1.CSZ)
+    pub fn context_size(&self) -> ContextSize {
+        self.memory().context_size
+    }
+
+    /// The highest physical address the xHC can access (from HCCPARAMS1.AC64)
+    pub fn max_phys_addr(&self) -> u64 {
+        self.memory().max_phys_addr
+    }
+
+    pub(crate) fn memory_mut(&mut self) -> &mut ControllerMemory<'a> {
+        self.memory
+            .as_mut()
+            .expect("a running controller always has memory")
+    }
+
+    /// Writes the current IMODI of `interrupter` to its IMOD register. The xHC uses it after the current interval ends.
+    pub(crate) fn write_imod(&mut self, interrupter: usize) {
+        let imodi = self.memory().moderation[interrupter].imodi;
+        self.runtime_regs
+            .as_mut_ptr()
+            .interrupter_register_sets()
+            .as_slice()
+            .index(interrupter)
+            .imod()
+            .update(|mut imod| {
+                imod.set_imodi(imodi);
+                imod
+            });
+    }
+
+    /// xHCI 5.6 Doorbell Registers: doorbell 0 is the Host Controller Command doorbell
+    pub(crate) fn ring_command_doorbell(&mut self) {
+        DoorbellManager::ring_command_doorbell(self.doorbell_regs.as_mut_ptr());
+    }
+
+    /// xHCI 5.6 Doorbell Registers: doorbells 1 to MaxSlotsEn are for device slots, and the target is the Device Context Index of the endpoint
+    pub(crate) fn ring_endpoint_doorbell(&mut self, slot_id: u8, dci: u8) {
+        DoorbellManager::ring_doorbell(self.doorbell_regs.as_mut_ptr(), slot_id, dci);
+    }
+
+    /// Clears Run/Stop (R/S) and waits for the xHC to halt.
+    ///
+    /// If the xHC doesn't halt, it could still be accessing memory, so the controller is returned as [`Running`].
+    pub fn halt(mut self, clock: &mut impl XhciClock) -> Result<Controller<'a, Configured>, Self> {
+        self.operational_regs
+            .as_mut_ptr()
+            .usb_cmd()
+            .update(|mut usb_cmd| {
+                usb_cmd.set_run_stop(false);
+                usb_cmd
+            });
+        let operational_regs = &self.operational_regs;
+        match poll_until(clock, HALT_TIMEOUT, || {
+            operational_regs.as_ptr().usb_sts().read().hc_halted()
+        }) {
+            Ok(()) => Ok(self.into_state()),
+            Err(Timeout) => Err(self),
+        }
+    }
+}
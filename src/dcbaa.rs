@@ -0,0 +1,70 @@
+use core::num::NonZero;
+
+use crate::*;
+
+/// xHCI 6.1 Device Context Base Address Array
+#[derive(Debug)]
+pub struct Dcbaa<'a> {
+    mem: XhciAllocation,
+    entries: &'a mut [u64],
+}
+
+impl Dcbaa<'_> {
+    pub fn new(
+        max_slots_en: u8,
+        page_size: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, InitError> {
+        // The Device Context Base Address Array shall contain MaxSlotsEn + 1 entries.
+        let dcbaa_len = max_slots_en as usize + 1;
+        let dcbaa_size = dcbaa_len * size_of::<u64>();
+        let dcbaa_mem = XhciAllocation::new(
+            allocator,
+            AllocRequest {
+                size: NonZero::new(dcbaa_size as u64)
+                    .ok_or(UnsupportedConfiguration::NoDeviceSlots)?,
+                align: XHCI_DEVICE_CONTEXT_ALIGNMENT,
+                boundary: xhci_device_context_boundary(page_size),
+                max_phys_addr,
+            },
+        )?;
+        // System software initializes the Device Context Base Address Array to ‘0’, and updates individual entries when the respective Device Slot is allocated. The xHC reads an entry in the Device Context after a doorbell associated with the entries’ Device Slot is rung.
+        let entries = unsafe { dcbaa_mem.response().as_zeroed_slice::<u64>(dcbaa_len) };
+        Ok(Self {
+            mem: dcbaa_mem,
+            entries,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.mem.phys_addr()
+    }
+
+    /// Entry 0 is the Scratchpad Buffer Array pointer, and entries 1..=MaxSlotsEn are Device Context pointers for each slot.
+    pub fn set(&mut self, index: u8, phys_addr: u64) {
+        self.entries[index as usize] = phys_addr;
+    }
+
+    /// The slot IDs that currently point to a Device Context
+    pub fn slots_in_use(&self) -> impl Iterator<Item = u8> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, entry)| **entry != 0)
+            .map(|(slot_id, _)| slot_id as u8)
+    }
+
+    /// Clears every Device Context pointer, but keeps the Scratchpad Buffer Array pointer.
+    /// Used when the xHC is reset and every device slot is disabled.
+    pub fn clear_device_contexts(&mut self) {
+        self.entries[1..].fill(0);
+    }
+
+    /// # Safety
+    /// The xHC must be halted, or DCBAAP must point to a different DCBAA.
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe { self.mem.free(allocator) };
+    }
+}
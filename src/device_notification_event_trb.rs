@@ -0,0 +1,50 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.7 Device Notification Event TRB
+/// Generated when a USB3 device sends a Device Notification Transaction Packet, and its type is enabled in DNCTRL.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciDeviceNotificationEventTrb {
+    pub parameter: DeviceNotificationEventParameter,
+    pub status: DeviceNotificationEventStatus,
+    pub control: DeviceNotificationEventControl,
+}
+
+event_trb!(
+    XhciDeviceNotificationEventTrb,
+    XhciTrbType::DeviceNotificationEvent
+);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DeviceNotificationEventParameter(u64);
+    impl Debug;
+
+    u8;
+    /// The Notification Type field of the Device Notification TP (USB 3 8.5.6)
+    pub notification_type, _: 7, 4;
+    u64;
+    /// Bytes 4 to 11 of the Device Notification TP, which depend on the Notification Type
+    pub device_notification_data, _: 63, 8;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DeviceNotificationEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DeviceNotificationEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+    u8; pub slot_id, _: 31, 24;
+}
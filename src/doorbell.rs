@@ -1,5 +1,3 @@
-use core::sync::atomic::AtomicPtr;
-
 use bitfield::bitfield;
 use num_enum::IntoPrimitive;
 use volatile::VolatilePtr;
@@ -0,0 +1,46 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.5 Doorbell Event TRB
+/// Only used with virtualization: it tells the Virtual Machine Manager that a Virtual Function's doorbell was rung.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciDoorbellEventTrb {
+    pub parameter: DoorbellEventParameter,
+    _reserved_0: u32,
+    pub status: DoorbellEventStatus,
+    pub control: DoorbellEventControl,
+}
+
+event_trb!(XhciDoorbellEventTrb, XhciTrbType::DoorbellEvent);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DoorbellEventParameter(u32);
+    impl Debug;
+
+    u8;
+    /// The DB Target that was written to the doorbell
+    pub db_reason, _: 4, 0;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DoorbellEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DoorbellEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+    u8; pub virtual_function_id, _: 23, 16;
+    u8; pub slot_id, _: 31, 24;
+}
@@ -1,352 +1,1099 @@
+use alloc::{collections::btree_map::Entry, vec, vec::Vec};
 use core::{
-    mem::MaybeUninit,
+    future::Future,
+    mem::ManuallyDrop,
     num::NonZero,
-    ptr::{NonNull, slice_from_raw_parts_mut},
+    pin::Pin,
+    ptr,
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use volatile::VolatileRef;
-use zerocopy::transmute_ref;
-
 use crate::*;
 
-pub struct Driver<'a> {
-    capability_regs: VolatileRef<'a, CapabilityRegs>,
-    operational_regs: VolatileRef<'a, OperationalRegs>,
-    runtime_regs: VolatileRef<'a, RuntimeRegisters>,
-    doorbell_regs: VolatileRef<'a, DoorbellArray>,
-    command_ring: CommandRing2<'a>,
-    event_ring: EventRing2<'a>,
+pub struct Driver<'a, A: XhciMemAllocator, C: XhciClock> {
+    // The controller owns memory, so it's in a `ManuallyDrop` because it can only be freed after the xHC is halted.
+    // `shutdown_inner` takes it out exactly once.
+    controller: ManuallyDrop<Controller<'a, Running>>,
+    allocator: A,
+    clock: C,
+    is_shut_down: bool,
 }
 
-impl Driver<'_> {
+/// A transfer ring segment can't be bigger than 64 KiB, which is 4096 TRBs
+const MAX_TRANSFER_RING_LEN: usize =
+    XHCI_TRANSFER_RING_SEGMENTS_MAX_SIZE.get() as usize / size_of::<AnyTrb>();
+
+impl<'a, A: XhciMemAllocator, C: XhciClock> Driver<'a, A, C> {
+    /// Takes the xHC from the BIOS, resets it, configures it and starts it.
+    ///
     /// Remember to have disable interrupts while this function is executing.
     /// Otherwise you could get an xHCI interrupt and cause a deadlock.
-    pub fn new(mmio: XhciMmio, allocator: &mut impl XhciMemAllocator) -> Self {
-        let capability_regs = {
-            let capability_regs_ptr = NonNull::new(mmio.addr.get() as *mut CapabilityRegs).unwrap();
-            unsafe { VolatileRef::new(capability_regs_ptr) }
-        };
-        log::debug!(
-            "Capability registers: {:#X?}",
-            capability_regs.as_ptr().read()
-        );
-        let mut operational_regs = {
-            let ptr = NonNull::new(
-                (mmio.addr.get() + capability_regs.as_ptr().cap_length().read() as usize)
-                    as *mut OperationalRegs,
-            )
-            .unwrap();
-            unsafe { VolatileRef::new(ptr) }
-        };
-        let mut runtime_regs = {
-            let ptr = NonNull::new(
-                (mmio.addr.get() + capability_regs.as_ptr().rts_off().read() as usize)
-                    as *mut RuntimeRegisters,
-            )
-            .unwrap();
-            unsafe { VolatileRef::new(ptr) }
-        };
-        let mut doorbell_regs = {
-            let ptr = NonNull::new(
-                (mmio.addr.get() + capability_regs.as_ptr().doorbell_offset().read() as usize)
-                    as *mut DoorbellArray,
+    pub fn new(
+        mmio: XhciMmio,
+        mut allocator: A,
+        mut clock: C,
+        config: DriverConfig,
+    ) -> Result<Self, InitError> {
+        match mmio.take_ownership_from_bios(&mut clock) {
+            Ok(()) => log::debug!("xHCI - Took ownership from BIOS"),
+            Err(Timeout) => {
+                log::warn!("xHCI - BIOS did not release ownership, taking it anyways")
+            }
+        }
+        let controller = Controller::reset(mmio, &mut clock)?.configure(config, &mut allocator)?;
+        let driver = Self::start(controller, allocator, clock)?;
+
+        for capability in unsafe { XhciExtendedCapabilities::new(driver.controller.mmio.addr) }
+            .into_iter()
+            .filter_map(|capability| capability.supported_protocol())
+        {
+            log::debug!("{capability:#X?}");
+        }
+
+        Ok(driver)
+    }
+
+    /// Use this instead of [`Driver::new`] if you went through the [`Controller`] stages yourself.
+    ///
+    /// # Safety
+    /// `allocator` must be the allocator that was passed to [`Controller::configure`], since the driver frees the controller's memory with it.
+    pub unsafe fn from_controller(
+        controller: Controller<'a, Running>,
+        allocator: A,
+        clock: C,
+    ) -> Self {
+        Self {
+            controller: ManuallyDrop::new(controller),
+            allocator,
+            clock,
+            is_shut_down: false,
+        }
+    }
+
+    fn into_parts(self) -> (Controller<'a, Running>, A, C) {
+        let mut driver = ManuallyDrop::new(self);
+        // `driver` is never dropped or used after this, so every field is moved out exactly once
+        unsafe {
+            (
+                ManuallyDrop::take(&mut driver.controller),
+                ptr::read(&driver.allocator),
+                ptr::read(&driver.clock),
             )
-            .unwrap();
-            unsafe { VolatileRef::new(ptr) }
-        };
+        }
+    }
 
-        // Before we initialize the host controller, we will reset it
-        operational_regs
-            .as_mut_ptr()
-            .usb_cmd()
-            .update(|mut usb_cmd| {
-                usb_cmd.set_host_controller_reset(true);
-                usb_cmd
-            });
-        // Wait until reset is done
-        loop {
-            if !operational_regs
-                .as_ptr()
-                .usb_cmd()
-                .read()
-                .host_controller_reset()
-                && !operational_regs
-                    .as_ptr()
-                    .usb_sts()
-                    .read()
-                    .controller_not_ready()
-            {
-                break;
+    /// The page size reported by the xHC's PAGESIZE register.
+    /// Anything that the spec says is "PAGESIZE" (for example buffers that you give the xHC) needs to use this.
+    pub fn page_size(&self) -> NonZero<u64> {
+        self.controller.page_size()
+    }
+
+    /// Whether the xHC uses 32 or 64 byte contexts (HCCPARAMS1.CSZ)
+    pub fn context_size(&self) -> ContextSize {
+        self.controller.context_size()
+    }
+
+    /// The highest physical address the xHC can access.
+    /// Any memory you give the xHC (like transfer buffers) has to be below this.
+    pub fn max_phys_addr(&self) -> u64 {
+        self.controller.max_phys_addr()
+    }
+
+    /// Allocates a zeroed Device Context laid out for this xHC's context size
+    pub fn alloc_device_context(&mut self) -> Result<DeviceContext, AllocError> {
+        DeviceContext::new(
+            self.context_size(),
+            self.page_size(),
+            self.max_phys_addr(),
+            &mut self.allocator,
+        )
+    }
+
+    /// # Safety
+    /// The DCBAA must not point to the Device Context anymore (see [`Self::set_device_context`]).
+    pub unsafe fn free_device_context(&mut self, device_context: DeviceContext) {
+        unsafe { device_context.free(&mut self.allocator) };
+    }
+
+    /// Allocates a zeroed Input Context laid out for this xHC's context size
+    pub fn alloc_input_context(&mut self) -> Result<InputContext, AllocError> {
+        InputContext::new(
+            self.context_size(),
+            self.page_size(),
+            self.max_phys_addr(),
+            &mut self.allocator,
+        )
+    }
+
+    /// # Safety
+    /// The xHC must not be executing a command that uses the Input Context.
+    pub unsafe fn free_input_context(&mut self, input_context: InputContext) {
+        unsafe { input_context.free(&mut self.allocator) };
+    }
+
+    /// Points the DCBAA entry for `slot_id` to `device_context`, or clears it if `None`.
+    /// This needs to be done after Enable Slot and before Address Device.
+    pub fn set_device_context(
+        &mut self,
+        slot_id: u8,
+        device_context: Option<&DeviceContext>,
+    ) -> Result<(), InvalidSlotId> {
+        let memory = self.controller.memory_mut();
+        if !(1..=memory.max_slots_en).contains(&slot_id) {
+            return Err(InvalidSlotId);
+        }
+        memory.dcbaa.set(
+            slot_id,
+            device_context.map_or(0, |device_context| device_context.phys_addr()),
+        );
+        Ok(())
+    }
+
+    /// Puts a command on the command ring and rings the command doorbell.
+    /// Use the [`CommandToken`] with [`Self::take_command_completion`] to get the result after the xHC finishes the command.
+    pub fn submit_command(
+        &mut self,
+        command: impl Command,
+    ) -> Result<CommandToken, SubmitCommandError> {
+        self.check_command_pointer(&command)?;
+        let command_ring = &mut self.controller.memory_mut().command_ring;
+        let trb_phys_addr = command_ring
+            .try_enqueue(command.into(), CommandCompletion::Stored(None))
+            .map_err(|EnqueueError::IsFull| SubmitCommandError::CommandRingFull)?;
+        // During an abort, the ring is restarted after the Command Ring Stopped event instead
+        if !command_ring.is_aborting() {
+            self.controller.ring_command_doorbell();
+        }
+        Ok(CommandToken { trb_phys_addr })
+    }
+
+    /// Like [`Self::submit_command`], but the Command Completion Event is sent to the returned future instead.
+    ///
+    /// If the command ring is full and [`DriverConfig::command_overflow_queue_len`] isn't 0, the command waits in memory
+    /// and is put on the command ring from [`Self::handle_interrupt`] once earlier commands complete.
+    pub fn submit_command_async(
+        &mut self,
+        command: impl Command,
+    ) -> Result<CommandFuture, SubmitCommandError> {
+        self.check_command_pointer(&command)?;
+        let (completer, future) = completion();
+        let command_ring = &mut self.controller.memory_mut().command_ring;
+        let is_on_ring = command_ring
+            .enqueue_or_queue(command.into(), completer)
+            .map_err(|EnqueueError::IsFull| SubmitCommandError::CommandRingFull)?;
+        // During an abort, the ring is restarted after the Command Ring Stopped event instead
+        if is_on_ring && !command_ring.is_aborting() {
+            self.controller.ring_command_doorbell();
+        }
+        Ok(future)
+    }
+
+    /// The memory that a command points to has to be 16 byte aligned, and the xHC has to be able to access it
+    fn check_command_pointer(&self, command: &impl Command) -> Result<(), SubmitCommandError> {
+        match command.pointer() {
+            Some(pointer) if pointer % 16 != 0 || pointer > self.max_phys_addr() => {
+                Err(SubmitCommandError::InvalidPointer)
             }
+            _ => Ok(()),
         }
-        log::debug!("xHCI - Reset host controller");
+    }
 
-        // xHCI 4 Operational Model
-        // xHCI 4.2 Host Controller Initialization
-        // Program the Max Device Slots Enabled (MaxSlotsEn) field in the CONFIG register (5.4.7) to enable the device slots that system software is going to use.
-        let max_slots = capability_regs.as_ptr().hcs_params_1().read().max_slots();
-        operational_regs.as_mut_ptr().config().update(|mut config| {
-            config.set_max_slots_en(max_slots);
-            config
+    /// xHCI 4.6.1.2 Aborting a Command
+    /// Use this when a command doesn't complete, which can happen with misbehaving devices (especially during Address Device).
+    ///
+    /// The oldest command that didn't complete is aborted, and it completes with [`CompletionCode::CommandAborted`].
+    /// If the xHC stopped before starting it, it's turned into a No Op, which also completes with Command Aborted.
+    /// The commands after it stay on the ring. When [`Self::handle_interrupt`] gets the Command Ring Stopped event, the ring is restarted.
+    ///
+    /// If CRR doesn't clear, the xHC is stuck. Treat it like a [`FatalError`] and use [`Self::recover`].
+    pub fn abort_command(&mut self) -> Result<(), AbortCommandError> {
+        if !self
+            .controller
+            .operational_regs
+            .as_ptr()
+            .crcr()
+            .read()
+            .crr()
+        {
+            return Err(AbortCommandError::NotRunning);
+        }
+        self.controller.memory_mut().command_ring.start_abort();
+        let crcr = self.controller.operational_regs.as_mut_ptr().crcr();
+        // While CRR is 1, the xHC ignores everything we write to CRCR except for CS and CA
+        crcr.update(|mut crcr| {
+            crcr.set_ca(true);
+            crcr
         });
+        poll_until(&mut self.clock, COMMAND_ABORT_TIMEOUT, || {
+            !crcr.read().crr()
+        })
+        .map_err(|Timeout| AbortCommandError::Timeout)
+    }
 
-        // 6.1 Device Context Base Address Array
-        // The Device Context Base Address Array shall contain MaxSlotsEn + 1 entries.
-        let dcbaa_len = max_slots as usize + 1;
-        let dcbaa_size = dcbaa_len * size_of::<u64>();
-        let dcbaa_mem = allocator.alloc(AllocRequest {
-            size: NonZero::new(dcbaa_size as u64).unwrap(),
-            align: XHCI_DEVICE_CONTEXT_ALIGNMENT,
-            boundary: XHCI_DEVICE_CONTEXT_BOUNDARY,
-        });
-        // System software initializes the Device Context Base Address Array to ‘0’, and updates individual entries when the respective Device Slot is allocated. The xHC reads an entry in the Device Context after a doorbell associated with the entries’ Device Slot is rung.
-        let dcbaa = {
-            let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                dcbaa_mem.virt_addr.get() as *mut MaybeUninit<u64>,
-                dcbaa_len,
-            ))
-            .unwrap();
-            unsafe { ptr.as_mut() }
+    /// Returns the Command Completion Event for a command from [`Self::submit_command`], if it arrived.
+    /// Completion events are received in [`Self::handle_interrupt`].
+    ///
+    /// Once this returns `Some`, the completion is forgotten, so it only returns `Some` once for each token.
+    pub fn take_command_completion(
+        &mut self,
+        token: &CommandToken,
+    ) -> Option<XhciCommandCompletionEventTrb> {
+        let command_ring = &mut self.controller.memory_mut().command_ring;
+        let completion = command_ring.take_completion(token.trb_phys_addr);
+        // A command from the overflow queue could have been waiting for this TRB
+        if command_ring.take_doorbell_needed() {
+            self.controller.ring_command_doorbell();
+        }
+        completion
+    }
+
+    /// Allocates a transfer ring for an endpoint.
+    /// `dci` is the Device Context Index (1 for the Default Control Endpoint), and `len` is the number of TRBs including the Link TRB (2 to 4096).
+    /// The Transfer Events of the endpoint go to the event ring of `interrupter`.
+    ///
+    /// Put the returned value in the TR Dequeue Pointer field of the endpoint's Endpoint Context in the Input Context,
+    /// before the Address Device or Configure Endpoint Command that enables the endpoint.
+    pub fn alloc_transfer_ring(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        len: usize,
+        interrupter: u16,
+    ) -> Result<TrDequeuePointer, AllocTransferRingError> {
+        let max_phys_addr = self.max_phys_addr();
+        let memory = self.controller.memory_mut();
+        if !(1..=memory.max_slots_en).contains(&slot_id) {
+            return Err(AllocTransferRingError::InvalidSlotId);
+        }
+        if !(1..=31).contains(&dci) {
+            return Err(AllocTransferRingError::InvalidEndpoint);
+        }
+        if !(2..=MAX_TRANSFER_RING_LEN).contains(&len) {
+            return Err(AllocTransferRingError::InvalidLen);
+        }
+        if interrupter as usize >= memory.event_rings.len() {
+            return Err(AllocTransferRingError::InvalidInterrupter);
+        }
+        let Entry::Vacant(entry) = memory.transfer_rings.entry((slot_id, dci)) else {
+            return Err(AllocTransferRingError::AlreadyAllocated);
         };
-        dcbaa.fill(MaybeUninit::zeroed());
-
-        // If the Max Scratchpad Buffers field of the HCSPARAMS2 register is > ‘0’, then the first entry (entry_0) in the DCBAA shall contain a pointer to the Scratchpad Buffer Array.
-        // If the Max Scratchpad Buffers field of the HCSPARAMS2 register is = ‘0’, then the first entry (entry_0) in the DCBAA is reserved and shall be cleared to ‘0’ by software.
-        // xHCI 4.20 Scratchpad Buffers
-        // 1. Software examines the Max Scratchpad Buffers Hi and Lo fields in the HCSPARAMS2 register.
-        if let Some(max_scratchpad_buffers) = NonZero::new(
-            capability_regs
-                .as_ptr()
-                .hcs_params_2()
-                .read()
-                .max_scratchpad_buffers(),
-        ) {
-            // 2. Software allocates a Scratchpad Buffer Array with Max Scratchpad Buffers entries.
-            let scratchpad_array_len = max_scratchpad_buffers.get() as usize;
-            let scratchpad_array_size = scratchpad_array_len * size_of::<u64>();
-            let scratchpad_array_mem = allocator.alloc(AllocRequest {
-                size: NonZero::new(scratchpad_array_size as u64)
-                    .expect("scratchpad array is not empty"),
-                align: XHCI_SCRATCHPAD_BUFFER_ARRAY_ALIGNMENT,
-                boundary: XHCI_SCRATCHPAD_BUFFER_ARRAY_BOUNDARY,
-            });
-            let scratchpad_array = {
-                let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                    scratchpad_array_mem.virt_addr.get() as *mut MaybeUninit<u64>,
-                    scratchpad_array_len,
-                ))
-                .unwrap();
-                unsafe { ptr.as_mut() }
-            };
-            // 3. Software writes the base address of the Scratchpad Buffer Array to the DCBAA (Slot 0) entry.
-            dcbaa[0].write(scratchpad_array_mem.phys_addr);
-            // 4. For each entry in the Scratchpad Buffer Array:
-            for scratchpad_array_entry in scratchpad_array {
-                // a. Software allocates a PAGESIZE Scratchpad Buffer.
-                let scratchpad_buffer_size = PAGE_SIZE;
-                let scratchpad_buffer_mem = allocator.alloc(AllocRequest {
-                    size: scratchpad_buffer_size,
-                    align: XHCI_SCRATCHPAD_BUFFERS_ALIGNMENT,
-                    boundary: XHCI_SCRATCHPAD_BUFFERS_BOUNDARY,
-                });
-                let scratchpad_buffer = {
-                    let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                        scratchpad_buffer_mem.virt_addr.get() as *mut MaybeUninit<u8>,
-                        scratchpad_array_size,
-                    ))
-                    .unwrap();
-                    unsafe { ptr.as_mut() }
-                };
-                // b. Software clears the Scratchpad Buffer to ‘0’.
-                scratchpad_buffer.fill(MaybeUninit::zeroed());
-                // c. Software writes the base address of the allocated Scratchpad Buffer to associated entry in the Scratchpad Buffer Array.
-                scratchpad_array_entry.write(scratchpad_buffer_mem.phys_addr);
+        let transfer_ring =
+            TransferRing::new(len, interrupter, max_phys_addr, &mut self.allocator)?;
+        Ok(entry.insert(transfer_ring).tr_dequeue_pointer())
+    }
+
+    /// Frees the transfer ring of an endpoint. Transfers that are still on it are cancelled.
+    ///
+    /// # Safety
+    /// The xHC must not use the ring anymore. The endpoint has to be dropped with a Configure Endpoint Command, or its slot has to be disabled
+    /// (this is also true for the slots returned by [`Self::recover`]).
+    pub unsafe fn free_transfer_ring(&mut self, slot_id: u8, dci: u8) -> Result<(), TransferError> {
+        let transfer_ring = self
+            .controller
+            .memory_mut()
+            .transfer_rings
+            .remove(&(slot_id, dci))
+            .ok_or(TransferError::NoTransferRing)?;
+        unsafe { transfer_ring.free(&mut self.allocator) };
+        Ok(())
+    }
+
+    /// xHCI 4.10.2.1: when a transfer fails (for example with a Stall), the xHC halts the endpoint.
+    /// This cancels every transfer that is still on the endpoint's transfer ring, and returns the TR Dequeue Pointer that skips them.
+    /// Use it in a [`SetTrDequeuePointerCommandTrb`] after the [`ResetEndpointCommandTrb`].
+    ///
+    /// It's also how transfers on an endpoint that was stopped with a [`StopEndpointCommandTrb`] are cancelled.
+    /// Stopped transfers stay pending until then, so don't free their buffers before the Set TR Dequeue Pointer Command completes.
+    pub fn skip_pending_transfers(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+    ) -> Result<TrDequeuePointer, TransferError> {
+        Ok(self
+            .controller
+            .memory_mut()
+            .transfer_rings
+            .get_mut(&(slot_id, dci))
+            .ok_or(TransferError::NoTransferRing)?
+            .skip_pending())
+    }
+
+    /// xHCI 4.11.2.2 Control transfers on the Default Control Endpoint of `slot_id`.
+    /// `data` is the buffer for the Data Stage, and its length must be wLength. If wLength is 0, there is no Data Stage and `data` is ignored.
+    ///
+    /// The future gets the Transfer Event of the Status Stage, or of the stage that failed.
+    pub fn control_transfer(
+        &mut self,
+        slot_id: u8,
+        setup: SetupPacket,
+        data: Option<TransferBuffer>,
+    ) -> Result<TransferFuture, TransferError> {
+        if data.map_or(0, |data| data.len) != setup.length as u32 {
+            return Err(TransferError::LengthMismatch);
+        }
+        // xHCI 4.11.2.2: a request without data has no Data Stage, and its Setup Stage has TRT = No Data Stage
+        let data = data.filter(|_| setup.length != 0);
+        let mut trbs = vec![SetupStageTrb::new(setup, data.is_some()).into()];
+        if let Some(data) = data {
+            if !data.fits_in_trb(self.max_phys_addr()) {
+                return Err(TransferError::InvalidBuffer);
             }
+            trbs.push(DataStageTrb::new(data, setup.is_in()).into());
         }
+        trbs.push(StatusStageTrb::new(setup, data.is_some()).into());
+        self.enqueue_transfer(slot_id, 1, &trbs)
+    }
 
-        // Program the Device Context Base Address Array Pointer (DCBAAP) register (5.4.6) with a 64-bit address pointing to where the Device Context Base Address Array is located.
-        operational_regs.as_mut_ptr().dcbaap().update(|mut dcbaap| {
-            dcbaap.set_dcbaap(dcbaa_mem.phys_addr);
-            dcbaap
-        });
+    /// xHCI 4.11.2.1 Normal TRB transfers, which are used for bulk and interrupt endpoints.
+    /// The direction comes from the endpoint. `dci` is the Device Context Index of the endpoint.
+    ///
+    /// If the device sends less than `buffer.len` bytes, the Transfer Event has a Short Packet completion code,
+    /// and the TRB Transfer Length is the number of bytes that weren't transferred.
+    pub fn normal_transfer(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        buffer: TransferBuffer,
+    ) -> Result<TransferFuture, TransferError> {
+        if !buffer.fits_in_trb(self.max_phys_addr()) {
+            return Err(TransferError::InvalidBuffer);
+        }
+        self.enqueue_transfer(slot_id, dci, &[NormalTrb::new(buffer).into()])
+    }
 
-        // Define the Command Ring Dequeue Pointer by programming the Command Ring Control Register (5.4.5) with a 64-bit address pointing to the starting address of the first TRB of the Command Ring.
-        let mut command_ring =
-            CommandRing2::new(256, operational_regs.as_mut_ptr().crcr(), allocator);
-
-        // Initialize each active interrupter by:
-        // Defining the Event Ring: (refer to section 4.9.4 for a discussion of Event Ring Management.)
-        // Software maintains an Event Ring Consumer Cycle State (CCS) bit, initializing it to ‘1’ and toggling it every time the Event Ring Dequeue Pointer wraps back to the beginning of the Event Ring.
-
-        // Allocate and initialize the Event Ring Segment(s).
-        let event_ring = EventRing2::new(256, allocator);
-
-        // Allocate the Event Ring Segment Table (ERST) (section 6.5).
-        // To keep things simple we'll only have a single segment
-        let event_ring_segment_table_len = 1;
-        let event_ring_segment_table_size =
-            event_ring_segment_table_len * size_of::<XhciErstEntry>();
-        let event_ring_segment_table_mem = allocator.alloc(AllocRequest {
-            size: NonZero::new(event_ring_segment_table_size as u64).unwrap(),
-            align: XHCI_EVENT_RING_SEGMENT_TABLE_ALIGNMENT,
-            boundary: XHCI_EVENT_RING_SEGMENT_TABLE_BOUNDARY,
-        });
-        let event_ring_segment_table = {
-            let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                event_ring_segment_table_mem.virt_addr.get() as *mut MaybeUninit<XhciErstEntry>,
-                event_ring_segment_table_len,
-            ))
-            .unwrap();
-            unsafe { ptr.as_mut() }
-        };
-        // Initialize ERST table entries to point to and to define the size (in TRBs) of the respective Event Ring Segment.
-        event_ring_segment_table[0].write(XhciErstEntry {
-            ring_segment_base_address: event_ring.phys_addr(),
-            ring_segment_size: event_ring.len() as u16,
-            _reserved_0: [0; 6],
-        });
+    fn enqueue_transfer(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        trbs: &[AnyTrb],
+    ) -> Result<TransferFuture, TransferError> {
+        let transfer_ring = self
+            .controller
+            .memory_mut()
+            .transfer_rings
+            .get_mut(&(slot_id, dci))
+            .ok_or(TransferError::NoTransferRing)?;
+        let (completer, future) = completion();
+        transfer_ring
+            .try_enqueue(trbs, completer)
+            .map_err(|EnqueueError::IsFull| TransferError::TransferRingFull)?;
+        self.controller.ring_endpoint_doorbell(slot_id, dci);
+        Ok(future)
+    }
 
-        // Program the Interrupter Event Ring Segment Table Size (ERSTSZ) register (5.5.2.3.1) with the number of segments described by the Event Ring Segment Table.
-        runtime_regs
-            .as_mut_ptr()
-            .interrupter_register_sets()
-            .as_slice()
-            .index(0)
-            .erstsz()
-            .update(|mut erstsz| {
-                erstsz.set_erstsz(event_ring_segment_table_len as u16);
-                erstsz
-            });
+    /// Again, remember to disable interrupts while executing this fn
+    ///
+    /// Processes the event ring of `interrupter`, which is the interrupter (and MSI-X vector) that the interrupt came from.
+    /// Command Completion and Port Status Change Events always go to interrupter 0.
+    /// Transfer Events go to the interrupter that was given to [`Self::alloc_transfer_ring`],
+    /// and Bandwidth Request and Device Notification Events go to the Interrupter Target in the device's Slot Context.
+    ///
+    /// Every event is passed to `handler` after the driver processed it. Use `&mut ()` to ignore them.
+    ///
+    /// If the xHC stopped because of a [`FatalError`], no events are processed and you should use [`Driver::recover`].
+    /// The error is also passed to [`XhciEventHandler::on_fatal_error`].
+    pub fn handle_interrupt(
+        &mut self,
+        interrupter: u16,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<(), HandleInterruptError> {
+        if interrupter as usize >= self.controller.memory_mut().event_rings.len() {
+            return Err(HandleInterruptError::InvalidInterrupter);
+        }
+        Ok(self.handle_event_ring(interrupter, handler)?)
+    }
 
-        // Program the Interrupter Event Ring Dequeue Pointer (ERDP) register (5.5.2.3.3) with the starting address of the first segment described by the Event Ring Segment Table.
-        event_ring.update_erdp(
-            runtime_regs
+    /// [`Self::handle_interrupt`] for an interrupter that exists
+    fn handle_event_ring(
+        &mut self,
+        interrupter: u16,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<(), FatalError> {
+        self.check_fatal_error(handler)?;
+        let controller = &mut *self.controller;
+        let memory = controller
+            .memory
+            .as_mut()
+            .expect("a running controller always has memory");
+        // The xHC can write more events while we're handling these, so only handle the ones that are there now
+        let event_ring = &memory.event_rings[interrupter as usize];
+        let events_len = event_ring.peek().count();
+        let events = event_ring.peek().take(events_len);
+        for event in events.clone() {
+            memory.command_ring.process_event(event);
+        }
+        let mut transfer_events = 0;
+        for event in events {
+            match Event::from(*event) {
+                Event::CommandCompletion(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_command_complete(&event);
+                }
+                Event::Transfer(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    transfer_events += 1;
+                    let slot_id = event.control.slot_id();
+                    let dci = event.control.endpoint_id();
+                    match memory.transfer_rings.get_mut(&(slot_id, dci)) {
+                        Some(transfer_ring) => transfer_ring.process_event(&event),
+                        None => log::warn!(
+                            "xHCI - Got a Transfer Event for slot {slot_id} endpoint {dci}, which doesn't have a transfer ring"
+                        ),
+                    }
+                    handler.on_transfer_complete(&event);
+                }
+                Event::PortStatusChange(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_port_change(&event);
+                }
+                Event::BandwidthRequest(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_bandwidth_request(&event);
+                }
+                Event::Doorbell(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_doorbell(&event);
+                }
+                Event::HostController(event) => {
+                    log::warn!("xHCI - Host Controller Event: {event:#X?}");
+                    handler.on_host_controller_event(&event);
+                }
+                Event::DeviceNotification(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_device_notification(&event);
+                }
+                Event::MfindexWrap(event) => {
+                    log::debug!("Event: {event:#X?}");
+                    handler.on_mfindex_wrap(&event);
+                }
+                Event::Unknown(event) => {
+                    log::warn!("xHCI - Got an event with an unknown TRB type: {event:#X?}");
+                    handler.on_unknown_event(&event);
+                }
+            }
+        }
+        memory.event_rings[interrupter as usize].advance_dequeue_pointer(
+            events_len,
+            controller
+                .runtime_regs
                 .as_mut_ptr()
                 .interrupter_register_sets()
                 .as_slice()
-                .index(0)
+                .index(interrupter as usize)
                 .erdp(),
         );
+        if memory.moderation[interrupter as usize].adapt(transfer_events) {
+            self.controller.write_imod(interrupter as usize);
+        }
+        // Not only on Port Status Change Events, because there might not be one (see [`Self::reset_port_async`])
+        self.complete_port_resets();
+        if self
+            .controller
+            .memory_mut()
+            .command_ring
+            .take_doorbell_needed()
+        {
+            self.controller.ring_command_doorbell();
+        }
+        Ok(())
+    }
 
-        // Program the Interrupter Event Ring Segment Table Base Address (ERSTBA) register (5.5.2.3.2) with a 64-bit address pointer to where the Event Ring Segment Table is located.
-        // Note that writing the ERSTBA enables the Event Ring. Refer to section 4.9.4 for more information on the Event Ring registers and their initialization.
-        runtime_regs
-            .as_mut_ptr()
-            .interrupter_register_sets()
-            .as_slice()
-            .index(0)
-            .erstba()
-            .update(|mut erstba| {
-                erstba.set_erstba(event_ring_segment_table_mem.phys_addr);
-                erstba
+    /// Tells `handler` about HSE or HCE before returning it
+    fn check_fatal_error(&self, handler: &mut impl XhciEventHandler) -> Result<(), FatalError> {
+        match self.controller.fatal_error() {
+            Some(fatal_error) => {
+                log::error!("xHCI - Fatal error: {fatal_error:?}");
+                handler.on_fatal_error(fatal_error);
+                Err(fatal_error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Processes the events on every interrupter's event ring, for [`DriverConfig::polling_mode`].
+    /// Call it in a loop, or use the blocking helpers like [`Self::submit_command_blocking`].
+    ///
+    /// Events are passed to `handler` like in [`Self::handle_interrupt`].
+    pub fn poll(&mut self, handler: &mut impl XhciEventHandler) -> Result<(), FatalError> {
+        // Checked here too, because the xHC can stop without writing any events
+        self.check_fatal_error(handler)?;
+        // EINT (in USBSTS) and IP (in IMAN) are set even when interrupts are disabled.
+        // They're RW1C, so clear them before looking at the event rings, and the xHC sets them again for newer events.
+        let usb_sts = self.controller.operational_regs.as_mut_ptr().usb_sts();
+        if usb_sts.read().eint() {
+            usb_sts.update(|usb_sts| {
+                let mut new_usb_sts = usb_sts.to_neutral();
+                new_usb_sts.set_eint(true);
+                new_usb_sts
             });
+        }
+        // Resets can finish without any events, so this is checked even if every event ring is empty
+        self.complete_port_resets();
+        for interrupter in 0..self.controller.memory_mut().event_rings.len() {
+            self.controller
+                .runtime_regs
+                .as_mut_ptr()
+                .interrupter_register_sets()
+                .as_slice()
+                .index(interrupter)
+                .iman()
+                .update(|mut iman| {
+                    iman.set_interrupt_pending(true);
+                    iman
+                });
+            // The cycle bit of the TRB at the dequeue pointer tells us if there are events, even if the xHC is holding back IP because of moderation
+            if self.controller.memory_mut().event_rings[interrupter]
+                .peek()
+                .next()
+                .is_some()
+            {
+                self.handle_event_ring(interrupter as u16, handler)?;
+            }
+        }
+        Ok(())
+    }
 
-        // Defining the interrupts:
-        // Enable the MSI-X interrupt mechanism by setting the MSI-X Enable flag in the MSI-X Capability Structure Message Control register (5.2.8.3).
-        // It's not this driver's job to do this.
-        // From my experience, QEMU can do either legacy PCI interrupts or MSI-X interrupts. Both work.
-        // In theory on real hardware it must support MSI, MSI-X or both. Legacy interrupts may or may not work.
+    /// Calls [`Self::poll`] until `future` resolves, for when interrupts can't wake the task that's waiting for it.
+    /// Works with the futures from [`Self::submit_command_async`], [`Self::control_transfer`], [`Self::normal_transfer`] and [`Self::reset_port_async`].
+    ///
+    /// Other events that arrive while waiting are passed to `handler`, like in [`Self::poll`].
+    ///
+    /// Giving up after `timeout` doesn't stop the operation on the xHC.
+    /// Use [`Self::abort_command`] for a command, or a [`StopEndpointCommandTrb`] for a transfer.
+    /// A stopped transfer stays pending, because the xHC continues it the next time the doorbell is rung.
+    /// Its buffer is still in use until you cancel it with [`Self::skip_pending_transfers`] and a [`SetTrDequeuePointerCommandTrb`].
+    pub fn wait<T>(
+        &mut self,
+        mut future: EventFuture<T>,
+        timeout: Duration,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<T, WaitError> {
+        // The future is only polled from here, so it doesn't need to wake anything
+        let mut context = Context::from_waker(Waker::noop());
+        let start = self.clock.now();
+        loop {
+            self.poll(handler)?;
+            if let Poll::Ready(result) = Pin::new(&mut future).poll(&mut context) {
+                return result.map_err(|Cancelled| WaitError::Cancelled);
+            }
+            if self.clock.now().saturating_sub(start) >= timeout {
+                return Err(WaitError::Timeout);
+            }
+            self.clock.delay(POLL_INTERVAL);
+        }
+    }
 
-        // Initializing the Interval field of the Interrupt Moderation register (5.5.2.2) with the target interrupt moderation rate.
-        // We can leave it as 0x0, which will not throttle interrupts at all
+    /// [`Self::submit_command_async`] and [`Self::wait`] together
+    pub fn submit_command_blocking(
+        &mut self,
+        command: impl Command,
+        timeout: Duration,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<XhciCommandCompletionEventTrb, BlockingCommandError> {
+        let future = self.submit_command_async(command)?;
+        Ok(self.wait(future, timeout, handler)?)
+    }
 
-        // Enable system bus interrupt generation by writing a ‘1’ to the Interrupter Enable (INTE) flag of the USBCMD register (5.4.1).
-        operational_regs
-            .as_mut_ptr()
-            .usb_cmd()
-            .update(|mut usb_cmd| {
-                usb_cmd.set_interrupter_enable(true);
-                usb_cmd
-            });
+    /// [`Self::control_transfer`] and [`Self::wait`] together
+    pub fn control_transfer_blocking(
+        &mut self,
+        slot_id: u8,
+        setup: SetupPacket,
+        data: Option<TransferBuffer>,
+        timeout: Duration,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<XhciTransferEventTrb, BlockingTransferError> {
+        let future = self.control_transfer(slot_id, setup, data)?;
+        Ok(self.wait(future, timeout, handler)?)
+    }
+
+    /// [`Self::normal_transfer`] and [`Self::wait`] together
+    pub fn normal_transfer_blocking(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        buffer: TransferBuffer,
+        timeout: Duration,
+        handler: &mut impl XhciEventHandler,
+    ) -> Result<XhciTransferEventTrb, BlockingTransferError> {
+        let future = self.normal_transfer(slot_id, dci, buffer)?;
+        Ok(self.wait(future, timeout, handler)?)
+    }
 
-        // Enable the Interrupter by writing a ‘1’ to the Interrupt Enable (IE) field of the Interrupter Management register (5.5.2.1).
-        runtime_regs
+    /// xHCI 5.5.2.2 Interrupter Moderation
+    /// Sets the minimum time between interrupts from `interrupter`, which the xHC counts in 250 ns steps (up to about 16 ms).
+    /// 0 doesn't throttle interrupts at all. This turns off adaptive moderation.
+    pub fn set_moderation_interval(
+        &mut self,
+        interrupter: u16,
+        interval: Duration,
+    ) -> Result<(), ModerationError> {
+        let imodi = imodi_from_duration(interval).ok_or(ModerationError::IntervalTooLong)?;
+        *self.moderation_mut(interrupter)? = Moderation::fixed(imodi);
+        self.controller.write_imod(interrupter as usize);
+        Ok(())
+    }
+
+    /// Lets [`Self::handle_interrupt`] change the moderation interval of `interrupter` by itself, based only on how busy it is.
+    /// It's doubled when an interrupt has a lot of Transfer Events, and halved when an interrupt has only one.
+    /// Interrupts without any Transfer Events (like for command completions and port changes) don't change it.
+    ///
+    /// The driver doesn't know what kind of endpoints the Transfer Events are from. If latency-sensitive endpoints (like HID)
+    /// share the interrupter with bulk endpoints, bulk traffic will slow them down, so give them their own interrupter instead.
+    pub fn set_adaptive_moderation(
+        &mut self,
+        interrupter: u16,
+        adaptive: AdaptiveModeration,
+    ) -> Result<(), ModerationError> {
+        let min_imodi =
+            imodi_from_duration(adaptive.min_interval).ok_or(ModerationError::IntervalTooLong)?;
+        let max_imodi =
+            imodi_from_duration(adaptive.max_interval).ok_or(ModerationError::IntervalTooLong)?;
+        if min_imodi > max_imodi {
+            return Err(ModerationError::InvalidRange);
+        }
+        *self.moderation_mut(interrupter)? = Moderation {
+            imodi: min_imodi,
+            adaptive: Some((min_imodi, max_imodi)),
+        };
+        self.controller.write_imod(interrupter as usize);
+        Ok(())
+    }
+
+    fn moderation_mut(&mut self, interrupter: u16) -> Result<&mut Moderation, ModerationError> {
+        self.controller
+            .memory_mut()
+            .moderation
+            .get_mut(interrupter as usize)
+            .ok_or(ModerationError::InvalidInterrupter)
+    }
+
+    /// Resets a root hub port, which is needed for USB2 devices before they can be addressed.
+    /// `port_number` starts at 1, like in the spec.
+    /// Waits until the xHC finishes the reset and clears Port Reset Change (PRC).
+    pub fn reset_port(&mut self, port_number: u8) -> Result<(), PortResetError> {
+        self.start_port_reset(port_number)?;
+        let portsc = self
+            .controller
+            .port_regs
             .as_mut_ptr()
-            .interrupter_register_sets()
             .as_slice()
-            .index(0)
-            .iman()
-            .update(|mut iman| {
-                iman.set_interrupt_enable(true);
-                iman
-            });
+            .index(port_number as usize - 1)
+            .portsc();
+        // xHCI 4.19.5 Port Reset: when the reset completes, PR is cleared and PRC is set
+        poll_until(&mut self.clock, PORT_RESET_TIMEOUT, || {
+            let portsc = portsc.read();
+            !portsc.pr() && portsc.prc()
+        })
+        .map_err(|Timeout| PortResetError::Timeout)?;
+        // Clear PRC (it's RW1C) so the next reset can be detected
+        portsc.update(|portsc| {
+            let mut new_portsc = portsc.to_neutral();
+            new_portsc.set_prc(true);
+            new_portsc
+        });
+        Ok(())
+    }
 
-        // Write the USBCMD (5.4.1) to turn the host controller ON via setting the Run/Stop (R/S) bit to ‘1’. This operation allows the xHC to begin accepting doorbell references.
-        operational_regs
+    /// Like [`Self::reset_port`], but instead of waiting, the returned future is completed by [`Self::handle_interrupt`] or [`Self::poll`] once the reset is done.
+    /// There is no timeout, so use your executor's timers if you need one.
+    ///
+    /// xHCI 4.19.2 Port Status Change Event Generation (PSCEG): the xHC doesn't generate a Port Status Change Event when PRC gets set
+    /// if other change bits (like CSC) are still set on the port. Clear them before the reset, or the future will only
+    /// complete the next time there's an interrupt for something else (or [`Self::poll`] is called).
+    pub fn reset_port_async(&mut self, port_number: u8) -> Result<PortResetFuture, PortResetError> {
+        self.start_port_reset(port_number)?;
+        let (completer, future) = completion();
+        self.controller
+            .memory_mut()
+            .port_resets
+            .insert(port_number, completer);
+        Ok(future)
+    }
+
+    /// Completes the futures from [`Self::reset_port_async`] of the ports that finished resetting.
+    /// Other ports are left alone, so PRC stays set for whoever is polling PORTSC.
+    fn complete_port_resets(&mut self) {
+        let controller = &mut *self.controller;
+        let memory = controller
+            .memory
+            .as_mut()
+            .expect("a running controller always has memory");
+        let mut finished_ports = Vec::new();
+        for &port_number in memory.port_resets.keys() {
+            let portsc = controller
+                .port_regs
+                .as_mut_ptr()
+                .as_slice()
+                .index(port_number as usize - 1)
+                .portsc();
+            // xHCI 4.19.5 Port Reset: when the reset completes, PR is cleared and PRC is set
+            let value = portsc.read();
+            if !value.pr() && value.prc() {
+                portsc.write({
+                    let mut new_portsc = value.to_neutral();
+                    new_portsc.set_prc(true);
+                    new_portsc
+                });
+                finished_ports.push(port_number);
+            }
+        }
+        for port_number in finished_ports {
+            if let Some(completer) = memory.port_resets.remove(&port_number) {
+                completer.complete(());
+            }
+        }
+    }
+
+    /// Sets Port Reset (PR) on a root hub port
+    fn start_port_reset(&mut self, port_number: u8) -> Result<(), PortResetError> {
+        if self
+            .controller
+            .memory_mut()
+            .port_resets
+            .contains_key(&port_number)
+        {
+            return Err(PortResetError::AlreadyResetting);
+        }
+        let max_ports = self
+            .controller
+            .capability_regs
+            .as_ptr()
+            .hcs_params_1()
+            .read()
+            .max_ports();
+        if !(1..=max_ports).contains(&port_number) {
+            return Err(PortResetError::InvalidPort);
+        }
+        self.controller
+            .port_regs
             .as_mut_ptr()
-            .usb_cmd()
-            .update(|mut usb_cmd| {
-                usb_cmd.set_run_stop(true);
-                usb_cmd
+            .as_slice()
+            .index(port_number as usize - 1)
+            .portsc()
+            .update(|portsc| {
+                let mut new_portsc = portsc.to_neutral();
+                new_portsc.set_pr(true);
+                new_portsc
             });
+        Ok(())
+    }
 
-        // Send a command
-        for _ in 0..1 {
-            command_ring
-                .try_enqueue(enable_slot_command_trb())
-                .expect("the command ring is currently empty");
+    /// xHCI 4.23.2 Save and Restore State
+    /// Halts the xHC and makes it save its internal state, so that the system can enter a sleep state that removes its power (like S3).
+    /// Use [`SuspendedDriver::resume`] after waking up.
+    ///
+    /// Before calling this, stop any transfers and suspend the ports (put them in U3) that have devices on them.
+    ///
+    /// If the xHC doesn't halt, you get the [`Driver`] back and it's still running.
+    pub fn suspend(self) -> Result<SuspendedDriver<'a, A, C>, Self> {
+        let (controller, allocator, mut clock) = self.into_parts();
+        match controller.halt(&mut clock) {
+            Ok(controller) => Ok(SuspendedDriver {
+                controller: ManuallyDrop::new(controller.save_state(&mut clock)),
+                allocator,
+                clock,
+            }),
+            Err(controller) => Err(unsafe { Self::from_controller(controller, allocator, clock) }),
         }
+    }
 
-        // Ring the doorbell
-        doorbell_regs.as_mut_ptr().as_slice().index(0).write({
-            let mut reg = DoorbellRegister(0);
-            reg.set_db_target(HostControllerDoorbellTarget::CommandDoorbell.into());
-            reg
-        });
+    /// Gets the xHC working again after a [`FatalError`] by resetting it and initializing it again.
+    /// The rings and the DCBAA are reused, so nothing is allocated.
+    ///
+    /// Returns the slot IDs of the devices that were lost. Treat them as disconnected, and free their Device Contexts and transfer rings.
+    /// Commands and transfers that were pending are cancelled.
+    /// Devices that are still plugged in will show up again as connect status changes on their ports.
+    ///
+    /// If this fails, the xHC is reset and the memory is freed (unless the xHC couldn't be halted, then the memory is leaked).
+    pub fn recover(self) -> Result<(Self, Vec<u8>), InitError> {
+        let (controller, mut allocator, mut clock) = self.into_parts();
+        // The xHC clears Run/Stop by itself after an error, so this should be instant
+        let Ok(mut controller) = controller.halt(&mut clock) else {
+            return Err(InitError::HaltTimeout);
+        };
+        let lost_slots = match controller.reinitialize(&mut clock) {
+            Ok(lost_slots) => lost_slots,
+            Err(e) => {
+                if let Err(e) = controller.reset_and_free(&mut allocator, &mut clock) {
+                    log::error!("xHCI - Failed to reset host controller: {e:?}");
+                }
+                return Err(e);
+            }
+        };
+        Ok((Self::start(controller, allocator, clock)?, lost_slots))
+    }
 
-        // log::debug!(
-        //     "Operational registers: {:#X?}",
-        //     operational_regs.as_ptr().read()
-        // );
-        // log::debug!(
-        //     "Interrupter registers: {:#X?}",
-        //     runtime_regs
-        //         .as_mut_ptr()
-        //         .interrupter_register_sets()
-        //         .as_slice()
-        //         .index(0)
-        //         .read()
-        // );
-
-        for capability in unsafe { XhciExtendedCapabilities::new(mmio.addr) }
-            .into_iter()
-            .filter_map(|capability| capability.supported_protocol())
-        {
-            log::debug!("{capability:#X?}");
+    /// Starts a controller that was configured with `allocator`, or resets it and frees its memory if it doesn't start
+    fn start(
+        controller: Controller<'a, Configured>,
+        mut allocator: A,
+        mut clock: C,
+    ) -> Result<Self, InitError> {
+        match controller.start(&mut clock) {
+            Ok(controller) => Ok(unsafe { Self::from_controller(controller, allocator, clock) }),
+            Err((controller, e)) => {
+                if let Err(e) = controller.reset_and_free(&mut allocator, &mut clock) {
+                    log::error!("xHCI - Failed to reset host controller: {e:?}");
+                }
+                Err(e)
+            }
         }
+    }
 
-        Self {
-            capability_regs,
-            operational_regs,
-            runtime_regs,
-            doorbell_regs,
-            command_ring,
-            event_ring,
+    /// Stops the xHC and gives all of the memory the driver allocated back to the allocator.
+    /// At the end the xHC is reset, so that it's in the same state as after power-on and can be handed to another driver (or a kexec'd kernel).
+    ///
+    /// If you don't call this, it's done when the [`Driver`] is dropped, but errors can only be logged.
+    pub fn shutdown(mut self) -> Result<(), ShutdownError> {
+        self.shutdown_inner()
+    }
+
+    fn shutdown_inner(&mut self) -> Result<(), ShutdownError> {
+        if self.is_shut_down {
+            return Ok(());
         }
+        self.is_shut_down = true;
+
+        // This is basically xHCI 4.2 Host Controller Initialization in reverse.
+        // `is_shut_down` makes sure that we only take the controller out once, and it's never used after this.
+        let controller = unsafe { ManuallyDrop::take(&mut self.controller) };
+        // Until the xHC is halted it can still access our memory, so if it doesn't halt we have to leak the memory.
+        let controller = controller
+            .halt(&mut self.clock)
+            .map_err(|_| ShutdownError::HaltTimeout)?;
+        // Resetting sets DCBAAP, CRCR, ERSTBA and every other register that points to our memory back to 0
+        controller
+            .reset_and_free(&mut self.allocator, &mut self.clock)
+            .map_err(|_| ShutdownError::ResetTimeout)?;
+        log::debug!("xHCI - Shut down host controller");
+        Ok(())
     }
+}
 
-    /// Again, remember to disable interrupts while executing this fn
-    pub fn handle_interrupt(&mut self) {
-        let events = self.event_ring.peek();
-        for event in events {
-            self.command_ring.process_event(event);
+impl<A: XhciMemAllocator, C: XhciClock> Drop for Driver<'_, A, C> {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown_inner() {
+            log::error!("xHCI - Failed to shut down host controller: {e:?}");
         }
-        for event in events {
-            if event.control.trb_type() == XhciTrbType::CmdCompletionEvent.into() {
-                let event: &XhciCommandCompletionEventTrb = transmute_ref!(event);
-                log::debug!("Event: {event:#X?}");
-            } else {
-                panic!()
+    }
+}
+
+/// A [`Driver`] that was suspended with [`Driver::suspend`].
+/// The xHC is halted, so the only thing you can do is resume it.
+///
+/// Dropping it resets the xHC and frees all of the memory, just like dropping a [`Driver`].
+pub struct SuspendedDriver<'a, A: XhciMemAllocator, C: XhciClock> {
+    // Taken out exactly once, either in `into_parts` or in `drop`
+    controller: ManuallyDrop<Controller<'a, Suspended>>,
+    allocator: A,
+    clock: C,
+}
+
+impl<'a, A: XhciMemAllocator, C: XhciClock> SuspendedDriver<'a, A, C> {
+    fn into_parts(self) -> (Controller<'a, Suspended>, A, C) {
+        let mut driver = ManuallyDrop::new(self);
+        // `driver` is never dropped or used after this, so every field is moved out exactly once
+        unsafe {
+            (
+                ManuallyDrop::take(&mut driver.controller),
+                ptr::read(&driver.allocator),
+                ptr::read(&driver.clock),
+            )
+        }
+    }
+
+    /// Restores the xHC's state and starts it again.
+    /// If the xHC lost its state, it's reinitialized and you get [`ResumeOutcome::Reinitialized`],
+    /// which means that every device is gone and the ports have to be enumerated again.
+    ///
+    /// Remember to disable interrupts while this function is executing, just like [`Driver::new`].
+    /// If this fails, the xHC is reset and the memory is freed.
+    pub fn resume(self) -> Result<(Driver<'a, A, C>, ResumeOutcome), InitError> {
+        let (controller, mut allocator, mut clock) = self.into_parts();
+        let (controller, outcome) = match controller.restore_state(&mut clock) {
+            Ok(restored) => restored,
+            Err((controller, e)) => {
+                if let Err(e) = controller.reset_and_free(&mut allocator, &mut clock) {
+                    log::error!("xHCI - Failed to reset host controller: {e:?}");
+                }
+                return Err(e);
             }
+        };
+        Ok((Driver::start(controller, allocator, clock)?, outcome))
+    }
+}
+
+impl<A: XhciMemAllocator, C: XhciClock> Drop for SuspendedDriver<'_, A, C> {
+    fn drop(&mut self) {
+        // This is the only place the controller is taken out, since `into_parts` doesn't drop `self`
+        let controller = unsafe { ManuallyDrop::take(&mut self.controller) };
+        if let Err(e) = controller.reset_and_free(&mut self.allocator, &mut self.clock) {
+            log::error!("xHCI - Failed to reset host controller: {e:?}");
         }
-        self.event_ring.advance_dequeue_pointer(
-            events.len(),
-            self.runtime_regs
-                .as_mut_ptr()
-                .interrupter_register_sets()
-                .as_slice()
-                .index(0)
-                .erdp(),
-        );
     }
 }
+
+/// Identifies a command from [`Driver::submit_command`].
+/// The command's TRB is reused after the command ring wraps around, so take the completion before submitting that many more commands.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandToken {
+    trb_phys_addr: u64,
+}
+
+impl CommandToken {
+    /// The physical address of the command's TRB on the command ring, which is what its Command Completion Event points to
+    pub fn trb_phys_addr(&self) -> u64 {
+        self.trb_phys_addr
+    }
+}
+
+#[derive(Debug)]
+pub enum AbortCommandError {
+    /// Command Ring Running (CRR) is 0, so the xHC isn't executing a command
+    NotRunning,
+    /// CRR didn't clear within [`COMMAND_ABORT_TIMEOUT`]
+    Timeout,
+}
+
+#[derive(Debug)]
+pub enum SubmitCommandError {
+    /// Every TRB on the command ring is waiting to be executed by the xHC (and the overflow queue is full, for [`Driver::submit_command_async`])
+    CommandRingFull,
+    /// The memory that the command points to isn't 16 byte aligned, or is past [`Driver::max_phys_addr`]
+    InvalidPointer,
+}
+
+/// The slot ID is 0 or greater than the number of enabled slots
+#[derive(Debug)]
+pub struct InvalidSlotId;
+
+#[derive(Debug)]
+pub enum PortResetError {
+    /// The port number is 0 or greater than HCSPARAMS1.MaxPorts
+    InvalidPort,
+    /// The xHC didn't finish resetting the port within [`PORT_RESET_TIMEOUT`]
+    Timeout,
+    /// There is already a reset from [`Driver::reset_port_async`] going on for this port
+    AlreadyResetting,
+}
+
+#[derive(Debug)]
+pub enum HandleInterruptError {
+    /// The xHC stopped. Use [`Driver::recover`].
+    FatalError(FatalError),
+    /// The interrupter is not less than [`DriverConfig::interrupters`]
+    InvalidInterrupter,
+}
+
+impl From<FatalError> for HandleInterruptError {
+    fn from(value: FatalError) -> Self {
+        Self::FatalError(value)
+    }
+}
+
+/// [`Driver::wait`] gave up
+#[derive(Debug)]
+pub enum WaitError {
+    /// The xHC stopped. Use [`Driver::recover`].
+    FatalError(FatalError),
+    /// The event didn't arrive in time
+    Timeout,
+    /// The driver forgot about the operation (see [`Cancelled`])
+    Cancelled,
+}
+
+impl From<FatalError> for WaitError {
+    fn from(value: FatalError) -> Self {
+        Self::FatalError(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockingCommandError {
+    Submit(SubmitCommandError),
+    Wait(WaitError),
+}
+
+impl From<SubmitCommandError> for BlockingCommandError {
+    fn from(value: SubmitCommandError) -> Self {
+        Self::Submit(value)
+    }
+}
+
+impl From<WaitError> for BlockingCommandError {
+    fn from(value: WaitError) -> Self {
+        Self::Wait(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockingTransferError {
+    Transfer(TransferError),
+    Wait(WaitError),
+}
+
+impl From<TransferError> for BlockingTransferError {
+    fn from(value: TransferError) -> Self {
+        Self::Transfer(value)
+    }
+}
+
+impl From<WaitError> for BlockingTransferError {
+    fn from(value: WaitError) -> Self {
+        Self::Wait(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    /// The interrupter is not less than [`DriverConfig::interrupters`]
+    InvalidInterrupter,
+    /// The interval doesn't fit in IMODI (about 16 ms)
+    IntervalTooLong,
+    /// The minimum interval is longer than the maximum interval
+    InvalidRange,
+}
+
+#[derive(Debug)]
+pub enum AllocTransferRingError {
+    /// The slot ID is 0 or greater than the number of enabled slots
+    InvalidSlotId,
+    /// The Device Context Index is not between 1 and 31
+    InvalidEndpoint,
+    /// The length is not between 2 and 4096 TRBs
+    InvalidLen,
+    /// The interrupter is not less than [`DriverConfig::interrupters`]
+    InvalidInterrupter,
+    /// The endpoint already has a transfer ring
+    AlreadyAllocated,
+    AllocFailed(AllocError),
+}
+
+impl From<AllocError> for AllocTransferRingError {
+    fn from(value: AllocError) -> Self {
+        Self::AllocFailed(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    /// The endpoint doesn't have a transfer ring from [`Driver::alloc_transfer_ring`]
+    NoTransferRing,
+    /// There aren't enough free TRBs on the transfer ring
+    TransferRingFull,
+    /// The buffer is bigger than 64 KiB, crosses a 64 KiB boundary, or goes past [`Driver::max_phys_addr`]
+    InvalidBuffer,
+    /// wLength in the Setup Packet is not the length of the data buffer
+    LengthMismatch,
+}
+
+#[derive(Debug)]
+pub enum ShutdownError {
+    /// The xHC didn't set HCHalted (HCH) within [`HALT_TIMEOUT`].
+    /// Since it could still be accessing memory, none of the driver's memory was freed.
+    HaltTimeout,
+    /// The xHC halted and memory was freed, but it didn't finish resetting within [`RESET_TIMEOUT`]
+    ResetTimeout,
+}
@@ -0,0 +1,37 @@
+use num_enum::TryFromPrimitive;
+use zerocopy::transmute;
+
+use crate::*;
+
+/// xHCI 6.4.2 Event TRBs
+/// An event from the event ring, converted to the TRB struct for its type
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    Transfer(XhciTransferEventTrb),
+    CommandCompletion(XhciCommandCompletionEventTrb),
+    PortStatusChange(XhciPortStatusChangeEventTrb),
+    BandwidthRequest(XhciBandwidthRequestEventTrb),
+    Doorbell(XhciDoorbellEventTrb),
+    HostController(XhciHostControllerEventTrb),
+    DeviceNotification(XhciDeviceNotificationEventTrb),
+    MfindexWrap(XhciMfindexWrapEventTrb),
+    /// A TRB type that isn't an event TRB in xHCI 6.4.6, like a vendor defined one (48 to 63).
+    /// It should be ignored.
+    Unknown(AnyTrb),
+}
+
+impl From<AnyTrb> for Event {
+    fn from(value: AnyTrb) -> Self {
+        match XhciTrbType::try_from_primitive(value.control.trb_type()) {
+            Ok(XhciTrbType::TransferEvent) => Self::Transfer(transmute!(value)),
+            Ok(XhciTrbType::CmdCompletionEvent) => Self::CommandCompletion(transmute!(value)),
+            Ok(XhciTrbType::PortStatusChangeEvent) => Self::PortStatusChange(transmute!(value)),
+            Ok(XhciTrbType::BandwidthRequestEvent) => Self::BandwidthRequest(transmute!(value)),
+            Ok(XhciTrbType::DoorbellEvent) => Self::Doorbell(transmute!(value)),
+            Ok(XhciTrbType::HostControllerEvent) => Self::HostController(transmute!(value)),
+            Ok(XhciTrbType::DeviceNotificationEvent) => Self::DeviceNotification(transmute!(value)),
+            Ok(XhciTrbType::MfindexWrapEvent) => Self::MfindexWrap(transmute!(value)),
+            _ => Self::Unknown(value),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::*;
+
+/// Callbacks for the events that [`Driver::handle_interrupt`] gets from the event ring.
+/// Every method does nothing by default, so only implement the ones you care about.
+///
+/// The methods are called from inside [`Driver::handle_interrupt`], so they run in your interrupt handler and can't use the driver.
+/// Keep them short, like by queueing work for a task.
+/// They are called after the driver is done with the event, so futures for the event are already completed.
+pub trait XhciEventHandler {
+    /// A Command Completion Event, including ones for commands from [`Driver::submit_command`] and [`Driver::submit_command_async`]
+    fn on_command_complete(&mut self, _event: &XhciCommandCompletionEventTrb) {}
+
+    /// A Transfer Event, including ones for transfers from [`Driver::control_transfer`] and [`Driver::normal_transfer`]
+    fn on_transfer_complete(&mut self, _event: &XhciTransferEventTrb) {}
+
+    /// A Port Status Change Event, which means a change bit in the port's PORTSC was set (like Connect Status Change).
+    /// If it was for a reset from [`Driver::reset_port_async`], PRC was already cleared.
+    fn on_port_change(&mut self, _event: &XhciPortStatusChangeEventTrb) {}
+
+    fn on_bandwidth_request(&mut self, _event: &XhciBandwidthRequestEventTrb) {}
+
+    fn on_doorbell(&mut self, _event: &XhciDoorbellEventTrb) {}
+
+    /// Something went wrong that isn't tied to a command or transfer, like [`CompletionCode::EventRingFullError`]
+    fn on_host_controller_event(&mut self, _event: &XhciHostControllerEventTrb) {}
+
+    fn on_device_notification(&mut self, _event: &XhciDeviceNotificationEventTrb) {}
+
+    fn on_mfindex_wrap(&mut self, _event: &XhciMfindexWrapEventTrb) {}
+
+    /// An event with a TRB type that the driver doesn't know (see [`Event::Unknown`])
+    fn on_unknown_event(&mut self, _event: &AnyTrb) {}
+
+    /// The xHC stopped because of HSE or HCE. It's called right before the same error is returned, and no more events are processed.
+    /// Use [`Driver::recover`] to get the xHC working again.
+    fn on_fatal_error(&mut self, _error: FatalError) {}
+}
+
+/// Ignores every event
+impl XhciEventHandler for () {}
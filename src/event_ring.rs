@@ -1,104 +1,194 @@
-use core::{
-    mem::MaybeUninit,
-    num::NonZero,
-    ptr::{NonNull, slice_from_raw_parts_mut},
-};
+use alloc::vec::Vec;
+use core::num::NonZero;
 
-use split_slice::SplitSlice;
 use volatile::VolatilePtr;
+use zerocopy::FromZeros;
 
 use crate::*;
 
+/// xHCI 4.9.4 Event Ring Management
+/// The ring is made of segments that are described by the Event Ring Segment Table (ERST), which the ring owns too.
+/// Unlike the command ring, there are no Link TRBs. The xHC goes from the end of one segment to the start of the next one by itself.
+/// Positions in the ring are indexes into all of the segments put together.
 pub struct EventRing2<'a> {
-    mem: AllocResponse,
-    ring: &'a mut [AnyTrb],
+    segments: Vec<EventRingSegment<'a>>,
+    /// The number of TRBs in each segment
+    segment_len: usize,
+    /// xHCI 6.5 Event Ring Segment Table
+    erst_mem: XhciAllocation,
     dequeue_pointer: usize,
     consumer_cycle_state: bool,
 }
 
+struct EventRingSegment<'a> {
+    mem: XhciAllocation,
+    trbs: &'a mut [AnyTrb],
+}
+
 impl EventRing2<'_> {
-    pub fn new(len: usize, allocator: &mut impl XhciMemAllocator) -> Self {
-        let event_ring_len = len;
-        let event_ring_size = event_ring_len * size_of::<AnyTrb>();
-        let event_ring_mem = allocator.alloc(AllocRequest {
-            size: NonZero::new(event_ring_size as u64).unwrap(),
-            align: XHCI_EVENT_RING_SEGMENTS_ALIGNMENT,
-            boundary: XHCI_EVENT_RING_SEGMENTS_BOUNDARY,
-        });
-        let event_ring = {
-            {
-                let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                    event_ring_mem.virt_addr.get() as *mut MaybeUninit<AnyTrb>,
-                    event_ring_len,
-                ))
-                .unwrap();
-                let event_ring_uninit = unsafe { ptr.as_mut() };
-                // Initially when the TRB Ring is created in memory, or if it is ever re -initialized, all TRBs in the ring shall be cleared to ‘0’. This state represents an empty queue.
-                event_ring_uninit.fill(MaybeUninit::zeroed());
-            }
-            let mut ptr = NonNull::new(slice_from_raw_parts_mut(
-                event_ring_mem.virt_addr.get() as *mut AnyTrb,
-                event_ring_len,
-            ))
-            .unwrap();
-            unsafe { ptr.as_mut() }
-        };
-        Self {
-            mem: event_ring_mem,
-            ring: event_ring,
+    /// Allocates `segments` segments of `segment_len` TRBs each, and the ERST that points to them.
+    /// `segments` can't be more than 2^ERST Max (HCSPARAMS2), and the ERST has to fit in a page. [`DriverConfig`] checks both.
+    pub fn new(
+        segment_len: usize,
+        segments: usize,
+        page_size: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, InitError> {
+        let segment_size = NonZero::new((segment_len * size_of::<AnyTrb>()) as u64)
+            .ok_or(UnsupportedConfiguration::RingTooSmall)?;
+        // Allocate the Event Ring Segment Table (ERST) (section 6.5).
+        let erst_mem = XhciAllocation::new(
+            allocator,
+            AllocRequest {
+                size: NonZero::new((segments * size_of::<XhciErstEntry>()) as u64)
+                    .ok_or(UnsupportedConfiguration::RingTooSmall)?,
+                align: XHCI_EVENT_RING_SEGMENT_TABLE_ALIGNMENT,
+                boundary: xhci_event_ring_segment_table_boundary(page_size),
+                max_phys_addr,
+            },
+        )?;
+        let mut event_ring = Self {
+            segments: Vec::with_capacity(segments),
+            segment_len,
+            erst_mem,
             dequeue_pointer: 0,
             consumer_cycle_state: true,
+        };
+        // Allocate and initialize the Event Ring Segment(s).
+        for _ in 0..segments {
+            let segment_mem = match XhciAllocation::new(
+                allocator,
+                AllocRequest {
+                    size: segment_size,
+                    align: XHCI_EVENT_RING_SEGMENTS_ALIGNMENT,
+                    boundary: XHCI_EVENT_RING_SEGMENTS_BOUNDARY,
+                    max_phys_addr,
+                },
+            ) {
+                Ok(segment_mem) => segment_mem,
+                Err(e) => {
+                    // The xHC doesn't know about the ERST yet, so we can give everything back
+                    unsafe { event_ring.free(allocator) };
+                    return Err(e.into());
+                }
+            };
+            // Initially when the TRB Ring is created in memory, or if it is ever re -initialized, all TRBs in the ring shall be cleared to ‘0’. This state represents an empty queue.
+            let trbs = unsafe { segment_mem.response().as_zeroed_slice(segment_len) };
+            event_ring.segments.push(EventRingSegment {
+                mem: segment_mem,
+                trbs,
+            });
+        }
+        // Initialize ERST table entries to point to and to define the size (in TRBs) of the respective Event Ring Segment.
+        let erst = unsafe {
+            event_ring
+                .erst_mem
+                .response()
+                .as_uninit_slice::<XhciErstEntry>(segments)
+        };
+        for (entry, segment) in erst.iter_mut().zip(&event_ring.segments) {
+            entry.write(XhciErstEntry {
+                ring_segment_base_address: segment.mem.phys_addr(),
+                ring_segment_size: segment_len as u16,
+                _reserved_0: [0; 6],
+            });
+        }
+        Ok(event_ring)
+    }
+
+    /// For ERSTBA
+    pub fn erst_phys_addr(&self) -> u64 {
+        self.erst_mem.phys_addr()
+    }
+
+    /// The number of entries in the ERST, for ERSTSZ
+    pub fn erst_len(&self) -> u16 {
+        self.segments.len() as u16
+    }
+
+    /// # Safety
+    /// The xHC must be halted, or the interrupter's ERSTBA must point to a different ERST.
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe {
+            for segment in self.segments {
+                segment.mem.free(allocator);
+            }
+            self.erst_mem.free(allocator);
         }
     }
 
-    pub fn phys_addr(&self) -> u64 {
-        self.mem.phys_addr
+    /// The number of TRBs in all of the segments
+    fn len(&self) -> usize {
+        self.segments.len() * self.segment_len
     }
 
-    pub fn len(&self) -> usize {
-        self.ring.len()
+    fn trb(&self, index: usize) -> &AnyTrb {
+        &self.segments[index / self.segment_len].trbs[index % self.segment_len]
+    }
+
+    /// Puts the ring back into the state it was in right after it was allocated.
+    /// The xHC must not be using the ring, so it must be halted (or reset).
+    pub fn reinitialize(&mut self) {
+        for segment in &mut self.segments {
+            segment.trbs.zero();
+        }
+        self.dequeue_pointer = 0;
+        self.consumer_cycle_state = true;
+    }
+
+    /// Writes the physical address of the TRB at the dequeue pointer to ERDP, along with the segment it's in
+    fn write_dequeue_pointer(&self, erdp: &mut Erdp) {
+        let segment_index = self.dequeue_pointer / self.segment_len;
+        erdp.set_event_ring_dequeue_pointer(
+            self.segments[segment_index].mem.phys_addr()
+                + (self.dequeue_pointer % self.segment_len) as u64 * size_of::<AnyTrb>() as u64,
+        );
+        // xHCI 5.5.2.3.3: DESI is the low 3 bits of the index of the ERST entry for the segment that the dequeue pointer is in
+        erdp.set_desi((segment_index & 0b111) as u8);
     }
 
     pub fn update_erdp(&self, erdp: VolatilePtr<Erdp>) {
         erdp.update(|mut erdp| {
-            erdp.set_event_ring_dequeue_pointer(self.mem.phys_addr);
+            self.write_dequeue_pointer(&mut erdp);
             erdp
         });
     }
 
-    pub fn peek(&self) -> SplitSlice<AnyTrb> {
-        for (i, trb) in self.ring[self.dequeue_pointer..].iter().enumerate() {
-            if trb.control.cycle_bit() != self.consumer_cycle_state {
-                return SplitSlice(&self.ring[self.dequeue_pointer..i], &[]);
+    /// The events that the xHC wrote since the dequeue pointer, oldest first
+    pub fn peek(&self) -> impl Iterator<Item = &AnyTrb> + Clone {
+        let mut index = self.dequeue_pointer;
+        let mut cycle_state = self.consumer_cycle_state;
+        // If every TRB has the cycle state we're looking for, the ring is full
+        (0..self.len()).map_while(move |_| {
+            let trb = self.trb(index);
+            if trb.control.cycle_bit() != cycle_state {
+                return None;
             }
-        }
-        // At this point we will loop around
-        let next_cycle_state = !self.consumer_cycle_state;
-        for (i, trb) in self.ring[..self.dequeue_pointer].iter().enumerate() {
-            if trb.control.cycle_bit() != next_cycle_state {
-                return SplitSlice(&self.ring[self.dequeue_pointer..], &self.ring[..i]);
+            index += 1;
+            // The xHC toggles its cycle state when it goes from the last segment back to the first one
+            if index == self.len() {
+                index = 0;
+                cycle_state = !cycle_state;
             }
-        }
-        // At this point every single slot is full
-        SplitSlice(
-            &self.ring[self.dequeue_pointer..],
-            &self.ring[..self.dequeue_pointer],
-        )
+            Some(trb)
+        })
     }
 
     pub fn advance_dequeue_pointer(&mut self, advance_len: usize, erdp: VolatilePtr<Erdp>) {
         // Check that we aren't advancing to an invalid state
-        let max_advance_len = self.peek().len();
+        let max_advance_len = self.peek().count();
         assert!(
             advance_len <= max_advance_len,
             "must advance only the amount that was consumed"
         );
-        self.dequeue_pointer =
-            wrapping_add_custom(self.dequeue_pointer, advance_len, self.ring.len());
+        self.dequeue_pointer += advance_len;
+        if self.dequeue_pointer >= self.len() {
+            self.dequeue_pointer -= self.len();
+            self.consumer_cycle_state = !self.consumer_cycle_state;
+        }
         erdp.update(|mut erdp| {
-            erdp.set_event_ring_dequeue_pointer(
-                self.mem.phys_addr + self.dequeue_pointer as u64 * size_of::<AnyTrb>() as u64,
-            );
+            self.write_dequeue_pointer(&mut erdp);
             // Tell the xHC that we can receive more interrupts
             erdp.set_event_handler_busy(true);
             erdp
@@ -106,17 +196,118 @@ impl EventRing2<'_> {
     }
 }
 
-/// Handles integer overflows too
-fn wrapping_add_custom(position: usize, advance_len: usize, ring_size: usize) -> usize {
-    let mut new_position = position;
-    let mut len_left_to_advance = advance_len;
-    {
-        let advance_amount = (ring_size - position).min(len_left_to_advance);
-        len_left_to_advance -= advance_amount;
-        new_position += advance_amount;
+#[cfg(test)]
+mod tests {
+    use core::ptr::NonNull;
+
+    use super::*;
+
+    const SEGMENT_LEN: usize = 16;
+    const SEGMENTS: usize = 3;
+
+    fn event_ring() -> EventRing2<'static> {
+        EventRing2::new(
+            SEGMENT_LEN,
+            SEGMENTS,
+            NonZero::new(4096).unwrap(),
+            u64::MAX,
+            &mut TestAllocator,
+        )
+        .unwrap()
+    }
+
+    /// Writes events like the xHC would, starting at `index`
+    fn produce(event_ring: &mut EventRing2, index: usize, len: usize, cycle_bit: bool) {
+        for index in index..index + len {
+            let trb = &mut event_ring.segments[index / SEGMENT_LEN].trbs[index % SEGMENT_LEN];
+            trb.control
+                .set_trb_type(XhciTrbType::PortStatusChangeEvent.into());
+            trb.control.set_cycle_bit(cycle_bit);
+        }
     }
-    if new_position == ring_size {
-        new_position = len_left_to_advance;
+
+    /// Advances the dequeue pointer and returns what was written to ERDP
+    fn advance(event_ring: &mut EventRing2, advance_len: usize) -> Erdp {
+        let mut erdp = Erdp(0);
+        event_ring.advance_dequeue_pointer(advance_len, unsafe {
+            VolatilePtr::new(NonNull::from(&mut erdp))
+        });
+        assert!(erdp.event_handler_busy());
+        erdp
+    }
+
+    fn trb_phys_addr(event_ring: &EventRing2, segment: usize, index: usize) -> u64 {
+        event_ring.segments[segment].mem.phys_addr() + (index * size_of::<AnyTrb>()) as u64
+    }
+
+    #[test]
+    fn erst_points_to_segments() {
+        let event_ring = event_ring();
+        assert_eq!(event_ring.erst_len(), SEGMENTS as u16);
+        let erst = unsafe {
+            event_ring
+                .erst_mem
+                .response()
+                .as_uninit_slice::<XhciErstEntry>(SEGMENTS)
+        };
+        for (entry, segment) in erst.iter().zip(&event_ring.segments) {
+            let entry = unsafe { entry.assume_init_ref() };
+            assert_eq!(entry.ring_segment_base_address, segment.mem.phys_addr());
+            assert_eq!(entry.ring_segment_size, SEGMENT_LEN as u16);
+        }
+        unsafe { event_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn peek_crosses_segments() {
+        let mut event_ring = event_ring();
+        assert_eq!(event_ring.peek().count(), 0);
+        produce(&mut event_ring, 0, SEGMENT_LEN - 2, true);
+        assert_eq!(event_ring.peek().count(), SEGMENT_LEN - 2);
+        let erdp = advance(&mut event_ring, SEGMENT_LEN - 2);
+        assert_eq!(
+            erdp.event_ring_dequeue_pointer(),
+            trb_phys_addr(&event_ring, 0, SEGMENT_LEN - 2)
+        );
+        assert_eq!(erdp.desi(), 0);
+
+        // The xHC goes on to the next segment by itself
+        produce(&mut event_ring, SEGMENT_LEN - 2, 4, true);
+        assert_eq!(event_ring.peek().count(), 4);
+        let erdp = advance(&mut event_ring, 4);
+        assert_eq!(
+            erdp.event_ring_dequeue_pointer(),
+            trb_phys_addr(&event_ring, 1, 2)
+        );
+        assert_eq!(erdp.desi(), 1);
+        unsafe { event_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn wraps_on_last_segment() {
+        let mut event_ring = event_ring();
+        let len = SEGMENT_LEN * SEGMENTS;
+        // Every TRB has the cycle state we're looking for, so the ring is full
+        produce(&mut event_ring, 0, len, true);
+        assert_eq!(event_ring.peek().count(), len);
+        let erdp = advance(&mut event_ring, len - 2);
+        assert_eq!(
+            erdp.event_ring_dequeue_pointer(),
+            trb_phys_addr(&event_ring, 2, SEGMENT_LEN - 2)
+        );
+        assert_eq!(erdp.desi(), 2);
+
+        // The xHC toggles its cycle state when it goes back to the first segment, so the old events there are not new
+        produce(&mut event_ring, 0, 2, false);
+        assert_eq!(event_ring.peek().count(), 4);
+        let erdp = advance(&mut event_ring, 4);
+        assert!(!event_ring.consumer_cycle_state);
+        assert_eq!(
+            erdp.event_ring_dequeue_pointer(),
+            trb_phys_addr(&event_ring, 0, 2)
+        );
+        assert_eq!(erdp.desi(), 0);
+        assert_eq!(event_ring.peek().count(), 0);
+        unsafe { event_ring.free(&mut TestAllocator) };
     }
-    new_position
 }
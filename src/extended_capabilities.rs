@@ -3,7 +3,10 @@ use core::{fmt::Debug, marker::PhantomData, num::NonZero, ptr::NonNull};
 use bitfield::bitfield;
 use volatile::{VolatileFieldAccess, VolatileRef};
 
-use crate::capability_regs::{CapabilityRegs, CapabilityRegsVolatileFieldAccess};
+use crate::{
+    capability_regs::{CapabilityRegs, CapabilityRegsVolatileFieldAccess},
+    legacy_support::XhciUsbLegacySupportCapability,
+};
 
 #[derive(Clone)]
 pub struct XhciExtendedCapabilities<'a> {
@@ -70,13 +73,9 @@ impl<'a> Iterator for XhciExtendedCapabilitiesIterator<'a> {
         )
         .unwrap();
         let register = unsafe { VolatileRef::new(ptr) };
-        self.ptr = NonZero::new(register.as_ptr().read().next_xhci_extended_capability_ptr()).map(
-            |relative_offset| {
-                offset_u32s
-                    .checked_add(relative_offset.get() as u16)
-                    .expect("offset does not overflow")
-            },
-        );
+        // If the offset overflows, the capability list is broken and we just stop iterating
+        self.ptr = NonZero::new(register.as_ptr().read().next_xhci_extended_capability_ptr())
+            .and_then(|relative_offset| offset_u32s.checked_add(relative_offset.get() as u16));
         Some(XhciExtendedCapability { register })
     }
 }
@@ -95,7 +94,22 @@ pub struct XhciExtendedCapability<'a> {
     register: VolatileRef<'a, XhciExtendedCapabilityPointerRegister>,
 }
 
-impl XhciExtendedCapability<'_> {
+impl<'a> XhciExtendedCapability<'a> {
+    pub fn usb_legacy_support(&self) -> Option<VolatileRef<'a, XhciUsbLegacySupportCapability>> {
+        if self.register.as_ptr().read().capability_id() == 0x1 {
+            Some({
+                let ptr = self
+                    .register
+                    .as_ptr()
+                    .as_raw_ptr()
+                    .cast::<XhciUsbLegacySupportCapability>();
+                unsafe { VolatileRef::new(ptr) }
+            })
+        } else {
+            None
+        }
+    }
+
     pub fn supported_protocol(&self) -> Option<XhciSupportedProtocolCapability> {
         if self.register.as_ptr().read().capability_id() == 0x2 {
             Some({
@@ -0,0 +1,33 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.6 Host Controller Event TRB
+/// Reports problems that aren't tied to a command or transfer, like the event ring being full ([`CompletionCode::EventRingFullError`]).
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciHostControllerEventTrb {
+    _reserved_0: u64,
+    pub status: HostControllerEventStatus,
+    pub control: HostControllerEventControl,
+}
+
+event_trb!(XhciHostControllerEventTrb, XhciTrbType::HostControllerEvent);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct HostControllerEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct HostControllerEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+}
@@ -0,0 +1,55 @@
+use crate::*;
+
+/// Reasons why [`Driver::new`] can fail.
+/// If you get one of these, the controller should be left alone (or reset again later).
+#[derive(Debug)]
+pub enum InitError {
+    /// The xHC did not clear Host Controller Reset (HCRST) after we set it
+    ResetTimeout,
+    /// The xHC did not clear Controller Not Ready (CNR), so we cannot write to its registers
+    ControllerNotReady,
+    /// The xHC did not set HCHalted (HCH) after we cleared Run/Stop (R/S)
+    HaltTimeout,
+    /// The xHC did not clear HCHalted (HCH) after we set Run/Stop (R/S)
+    StartTimeout,
+    /// The [`XhciMemAllocator`] returned an [`AllocError`]
+    AllocFailed(AllocError),
+    /// Host Controller Error (HCE) was set in USBSTS. The xHC needs to be reset before it can be used again.
+    HostControllerError,
+    UnsupportedConfiguration(UnsupportedConfiguration),
+    /// The [`DriverConfig`] doesn't fit this xHC
+    InvalidConfig(ConfigError),
+}
+
+impl From<AllocError> for InitError {
+    fn from(value: AllocError) -> Self {
+        Self::AllocFailed(value)
+    }
+}
+
+impl From<ConfigError> for InitError {
+    fn from(value: ConfigError) -> Self {
+        Self::InvalidConfig(value)
+    }
+}
+
+impl From<UnsupportedConfiguration> for InitError {
+    fn from(value: UnsupportedConfiguration) -> Self {
+        Self::UnsupportedConfiguration(value)
+    }
+}
+
+/// The controller (or the way we were asked to set it up) is something this driver can't work with
+#[derive(Debug)]
+pub enum UnsupportedConfiguration {
+    /// HCSPARAMS1 reports 0 device slots
+    NoDeviceSlots,
+    /// HCSPARAMS1 reports 0 interrupters
+    NoInterrupters,
+    /// The PAGESIZE register doesn't have any bits set
+    InvalidPageSize,
+    /// A register offset in the capability registers points outside of the address space
+    InvalidRegisterOffset,
+    /// A TRB ring needs at least 1 TRB plus the Link TRB
+    RingTooSmall,
+}
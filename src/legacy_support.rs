@@ -0,0 +1,97 @@
+use bitfield::bitfield;
+use volatile::{
+    VolatileFieldAccess, VolatileRef,
+    access::{ReadOnly, ReadWrite},
+};
+
+use crate::*;
+
+/// xHCI 7.1 USB Legacy Support Capability
+/// The BIOS uses this to emulate a PS/2 keyboard with USB keyboards (using SMIs) until the OS takes the xHC from it.
+#[derive(Debug, VolatileFieldAccess)]
+#[repr(C)]
+pub struct XhciUsbLegacySupportCapability {
+    #[access(ReadOnly)]
+    capability_id: u8,
+    #[access(ReadOnly)]
+    next_capability_pointer: u8,
+    /// 7.1.1 USB Legacy Support Capability (USBLEGSUP) bit 16.
+    /// We access USBLEGSUP one byte at a time so that we never write to the BIOS's semaphore when setting ours.
+    #[access(ReadWrite)]
+    pub hc_bios_owned_semaphore: SemaphoreByte,
+    /// USBLEGSUP bit 24
+    #[access(ReadWrite)]
+    pub hc_os_owned_semaphore: SemaphoreByte,
+    #[access(ReadWrite)]
+    pub usb_leg_ctl_sts: UsbLegCtlSts,
+}
+
+bitfield! {
+    /// One of the semaphore bytes in USBLEGSUP. Only bit 0 is used, the rest are reserved.
+    #[derive(Clone, Copy)]
+    pub struct SemaphoreByte(u8);
+    impl Debug;
+
+    pub owned, set_owned: 0;
+}
+
+bitfield! {
+    /// xHCI 7.1.2 USB Legacy Support Control/Status (USBLEGCTLSTS)
+    #[derive(Clone, Copy)]
+    pub struct UsbLegCtlSts(u32);
+    impl Debug;
+
+    pub usb_smi_enable, set_usb_smi_enable: 0;
+    pub smi_on_host_system_error_enable, set_smi_on_host_system_error_enable: 4;
+    pub smi_on_os_ownership_enable, set_smi_on_os_ownership_enable: 13;
+    pub smi_on_pci_command_enable, set_smi_on_pci_command_enable: 14;
+    pub smi_on_bar_enable, set_smi_on_bar_enable: 15;
+    pub smi_on_event_interrupt, _: 16;
+    pub smi_on_host_system_error, _: 20;
+    pub smi_on_os_ownership_change, set_smi_on_os_ownership_change: 29;
+    pub smi_on_pci_command, set_smi_on_pci_command: 30;
+    pub smi_on_bar, set_smi_on_bar: 31;
+}
+
+/// xHCI 4.22.1 Pre-OS to OS Handoff Synchronization
+/// Sets the OS Owned semaphore, waits for the BIOS to clear the BIOS Owned semaphore, and then disables every SMI so that the firmware stops touching the xHC.
+/// If the BIOS doesn't let go within [`BIOS_HANDOFF_TIMEOUT`], we take the xHC anyways (this is what Linux does too), and return [`Timeout`] so the caller can log it.
+pub(crate) fn take_ownership(
+    mut capability: VolatileRef<XhciUsbLegacySupportCapability>,
+    clock: &mut impl XhciClock,
+) -> Result<(), Timeout> {
+    capability
+        .as_mut_ptr()
+        .hc_os_owned_semaphore()
+        .update(|mut semaphore| {
+            semaphore.set_owned(true);
+            semaphore
+        });
+    let result = poll_until(clock, BIOS_HANDOFF_TIMEOUT, || {
+        !capability.as_ptr().hc_bios_owned_semaphore().read().owned()
+    });
+    if result.is_err() {
+        // The BIOS is broken, so we clear its semaphore ourselves
+        capability
+            .as_mut_ptr()
+            .hc_bios_owned_semaphore()
+            .write(SemaphoreByte(0));
+    }
+
+    // Disable all SMIs, and clear the SMI status bits (which are RW1C)
+    capability
+        .as_mut_ptr()
+        .usb_leg_ctl_sts()
+        .update(|mut usb_leg_ctl_sts| {
+            usb_leg_ctl_sts.set_usb_smi_enable(false);
+            usb_leg_ctl_sts.set_smi_on_host_system_error_enable(false);
+            usb_leg_ctl_sts.set_smi_on_os_ownership_enable(false);
+            usb_leg_ctl_sts.set_smi_on_pci_command_enable(false);
+            usb_leg_ctl_sts.set_smi_on_bar_enable(false);
+            usb_leg_ctl_sts.set_smi_on_os_ownership_change(true);
+            usb_leg_ctl_sts.set_smi_on_pci_command(true);
+            usb_leg_ctl_sts.set_smi_on_bar(true);
+            usb_leg_ctl_sts
+        });
+    result
+}
@@ -1,39 +1,102 @@
 #![no_std]
 extern crate alloc;
 
+/// Lets an event TRB be converted from an [`AnyTrb`] that has the right TRB Type
+macro_rules! event_trb {
+    ($trb:ty, $trb_type:expr) => {
+        impl TryFrom<AnyTrb> for $trb {
+            type Error = TrbConvertError;
+            fn try_from(value: AnyTrb) -> Result<Self, Self::Error> {
+                let trb_type = value.control.trb_type();
+                if trb_type == $trb_type.into() {
+                    Ok(transmute!(value))
+                } else {
+                    Err(TrbConvertError::WrongType(trb_type))
+                }
+            }
+        }
+    };
+}
+
+mod bandwidth_request_event_trb;
 mod capability_regs;
+mod clock;
 mod command_completion_trb;
 mod command_ring;
+mod commands;
+mod completion;
+mod completion_code;
+mod config;
+mod context;
+mod controller;
+mod dcbaa;
+mod device_notification_event_trb;
 mod doorbell;
+mod doorbell_event_trb;
 mod driver;
-mod enable_slot_command_trb;
 mod erst;
+mod event;
+mod event_handler;
 mod event_ring;
 mod extended_capabilities;
+mod host_controller_event_trb;
+mod init_error;
 mod interrupter_regs;
+mod legacy_support;
 mod mem;
+mod mfindex_wrap_event_trb;
 mod mmio;
+mod moderation;
 mod operational_regs;
+mod port_regs;
+mod port_status_change_event_trb;
 mod runtime_regs;
+mod scratchpad;
+mod transfer_event_trb;
+mod transfer_ring;
+mod transfers;
 mod trb;
 mod trb_type;
 mod xhci_mem_allocator;
 
 use capability_regs::*;
-use command_completion_trb::*;
 use command_ring::*;
+use dcbaa::*;
 use doorbell::*;
-use enable_slot_command_trb::*;
 use erst::*;
 use event_ring::*;
 use extended_capabilities::*;
 use interrupter_regs::*;
+use legacy_support::*;
 use mem::*;
 use operational_regs::*;
+use port_regs::*;
 use runtime_regs::*;
-use trb::*;
-use trb_type::*;
+use scratchpad::*;
+use transfer_ring::*;
 
+pub use bandwidth_request_event_trb::*;
+pub use clock::*;
+pub use command_completion_trb::*;
+pub use commands::*;
+pub use completion::*;
+pub use completion_code::*;
+pub use config::*;
+pub use context::*;
+pub use controller::*;
+pub use device_notification_event_trb::*;
+pub use doorbell_event_trb::*;
 pub use driver::*;
+pub use event::*;
+pub use event_handler::*;
+pub use host_controller_event_trb::*;
+pub use init_error::*;
+pub use mfindex_wrap_event_trb::*;
 pub use mmio::*;
+pub use moderation::*;
+pub use port_status_change_event_trb::*;
+pub use transfer_event_trb::*;
+pub use transfers::*;
+pub use trb::*;
+pub use trb_type::*;
 pub use xhci_mem_allocator::*;
@@ -2,7 +2,10 @@
 
 use core::num::NonZero;
 
-pub const PAGE_SIZE: NonZero<u64> = NonZero::new(0x1000).unwrap();
+/// The smallest page size an xHC can have. The actual page size comes from the PAGESIZE register (see [`Controller::page_size`](crate::Controller::page_size)).
+pub const MIN_PAGE_SIZE: NonZero<u64> = NonZero::new(0x1000).unwrap();
+
+// Anything that is "PAGESIZE" in xHCI Table 6-1 depends on the PAGESIZE register, so it is a function of the page size instead of a constant.
 
 // Max sizes
 pub const XHCI_DEVICE_CONTEXT_INDEX_MAX_SIZE: NonZero<u64> = NonZero::new(2048).unwrap();
@@ -12,29 +15,55 @@ pub const XHCI_SLOT_CONTEXT_MAX_SIZE: NonZero<u64> = NonZero::new(64).unwrap();
 pub const XHCI_ENDPOINT_CONTEXT_MAX_SIZE: NonZero<u64> = NonZero::new(64).unwrap();
 pub const XHCI_STREAM_CONTEXT_MAX_SIZE: NonZero<u64> = NonZero::new(16).unwrap();
 pub const XHCI_STREAM_ARRAY_LINEAR_MAX_SIZE: NonZero<u64> = NonZero::new(1024 * 1024).unwrap(); // 1 MB
-pub const XHCI_STREAM_ARRAY_PRI_SEC_MAX_SIZE: NonZero<u64> = PAGE_SIZE;
+pub const fn xhci_stream_array_pri_sec_max_size(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
 pub const XHCI_TRANSFER_RING_SEGMENTS_MAX_SIZE: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
 pub const XHCI_COMMAND_RING_SEGMENTS_MAX_SIZE: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
 pub const XHCI_EVENT_RING_SEGMENTS_MAX_SIZE: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
 pub const XHCI_EVENT_RING_SEGMENT_TABLE_MAX_SIZE: NonZero<u64> = NonZero::new(1024 * 512).unwrap(); // 512 KB
 pub const XHCI_SCRATCHPAD_BUFFER_ARRAY_MAX_SIZE: NonZero<u64> = NonZero::new(248).unwrap();
-pub const XHCI_SCRATCHPAD_BUFFERS_MAX_SIZE: NonZero<u64> = PAGE_SIZE;
+pub const fn xhci_scratchpad_buffers_max_size(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
 
 // Boundaries
-pub const XHCI_DEVICE_CONTEXT_INDEX_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_DEVICE_CONTEXT_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_INPUT_CONTROL_CONTEXT_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_SLOT_CONTEXT_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_ENDPOINT_CONTEXT_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_STREAM_CONTEXT_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_STREAM_ARRAY_LINEAR_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_STREAM_ARRAY_PRI_SEC_BOUNDARY: NonZero<u64> = PAGE_SIZE;
+pub const fn xhci_device_context_index_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_device_context_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_input_control_context_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_slot_context_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_endpoint_context_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_stream_context_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_stream_array_linear_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_stream_array_pri_sec_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
 pub const XHCI_TRANSFER_RING_SEGMENTS_BOUNDARY: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
 pub const XHCI_COMMAND_RING_SEGMENTS_BOUNDARY: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
 pub const XHCI_EVENT_RING_SEGMENTS_BOUNDARY: NonZero<u64> = NonZero::new(1024 * 64).unwrap(); // 64 KB
-pub const XHCI_EVENT_RING_SEGMENT_TABLE_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_SCRATCHPAD_BUFFER_ARRAY_BOUNDARY: NonZero<u64> = PAGE_SIZE;
-pub const XHCI_SCRATCHPAD_BUFFERS_BOUNDARY: NonZero<u64> = PAGE_SIZE;
+pub const fn xhci_event_ring_segment_table_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_scratchpad_buffer_array_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
+pub const fn xhci_scratchpad_buffers_boundary(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
 
 // Alignments
 pub const XHCI_DEVICE_CONTEXT_INDEX_ALIGNMENT: NonZero<u64> = NonZero::new(64).unwrap();
@@ -50,4 +79,6 @@ pub const XHCI_COMMAND_RING_SEGMENTS_ALIGNMENT: NonZero<u64> = NonZero::new(64).
 pub const XHCI_EVENT_RING_SEGMENTS_ALIGNMENT: NonZero<u64> = NonZero::new(64).unwrap();
 pub const XHCI_EVENT_RING_SEGMENT_TABLE_ALIGNMENT: NonZero<u64> = NonZero::new(64).unwrap();
 pub const XHCI_SCRATCHPAD_BUFFER_ARRAY_ALIGNMENT: NonZero<u64> = NonZero::new(64).unwrap();
-pub const XHCI_SCRATCHPAD_BUFFERS_ALIGNMENT: NonZero<u64> = PAGE_SIZE;
+pub const fn xhci_scratchpad_buffers_alignment(page_size: NonZero<u64>) -> NonZero<u64> {
+    page_size
+}
@@ -0,0 +1,33 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.8 MFINDEX Wrap Event TRB
+/// Generated every time MFINDEX goes from 03FFFh to 0, if Enable Wrap Event (EWE) is set in USBCMD.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciMfindexWrapEventTrb {
+    _reserved_0: u64,
+    pub status: MfindexWrapEventStatus,
+    pub control: MfindexWrapEventControl,
+}
+
+event_trb!(XhciMfindexWrapEventTrb, XhciTrbType::MfindexWrapEvent);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct MfindexWrapEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct MfindexWrapEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+}
@@ -1,4 +1,8 @@
-use core::num::NonZero;
+use core::{num::NonZero, ptr::NonNull};
+
+use volatile::VolatileRef;
+
+use crate::*;
 
 #[derive(Debug)]
 pub struct XhciMmio {
@@ -13,4 +17,33 @@ impl XhciMmio {
     pub unsafe fn new(addr: NonZero<usize>) -> Self {
         Self { addr }
     }
+
+    /// Get a reference to the registers located `offset` bytes after the start of BAR 0.
+    /// Offsets come from the capability registers, so we don't trust them to not overflow.
+    pub(crate) fn registers<'a, T>(
+        &self,
+        offset: usize,
+    ) -> Result<VolatileRef<'a, T>, UnsupportedConfiguration> {
+        let ptr = self
+            .addr
+            .checked_add(offset)
+            .and_then(|addr| NonNull::new(addr.get() as *mut T))
+            .ok_or(UnsupportedConfiguration::InvalidRegisterOffset)?;
+        Ok(unsafe { VolatileRef::new(ptr) })
+    }
+
+    /// xHCI 4.22.1 Pre-OS to OS Handoff Synchronization
+    /// On PCs, the BIOS owns the xHC until we ask for it, and it could still be using it (and generating SMIs) for legacy keyboard emulation.
+    /// This has to be done before [`Controller::reset`]. It does nothing if the xHC doesn't have a USB Legacy Support capability.
+    ///
+    /// Returns [`Timeout`] if the BIOS didn't release ownership. The xHC is taken anyways, so this is only worth logging.
+    pub fn take_ownership_from_bios(&self, clock: &mut impl XhciClock) -> Result<(), Timeout> {
+        match unsafe { XhciExtendedCapabilities::new(self.addr) }
+            .into_iter()
+            .find_map(|capability| capability.usb_legacy_support())
+        {
+            Some(usb_legacy_support) => take_ownership(usb_legacy_support, clock),
+            None => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,122 @@
+use core::time::Duration;
+
+/// Bounds for adaptive (load-based) interrupt moderation (see [`Driver::set_adaptive_moderation`](crate::Driver::set_adaptive_moderation)).
+/// The interval starts at `min_interval` and moves between the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveModeration {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+}
+
+/// If an interrupt has at least this many Transfer Events, the interrupter is busy, so interrupts can be throttled more
+const BUSY_TRANSFER_EVENTS: usize = 8;
+/// If an interrupt has at most this many Transfer Events, the traffic is light, so latency matters more
+const IDLE_TRANSFER_EVENTS: usize = 1;
+
+/// xHCI 5.5.2.2 Interrupter Moderation of one interrupter
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Moderation {
+    /// The current value of IMODI
+    pub imodi: u16,
+    /// The minimum and maximum IMODI, if adaptive moderation is on
+    pub adaptive: Option<(u16, u16)>,
+}
+
+impl Moderation {
+    pub fn fixed(imodi: u16) -> Self {
+        Self {
+            imodi,
+            adaptive: None,
+        }
+    }
+
+    /// Doubles the interval when an interrupt had a lot of Transfer Events, and halves it when it had almost none.
+    /// Interrupts without Transfer Events don't say anything about the transfer traffic, so they're ignored.
+    /// Returns `true` if IMODI changed and needs to be written to the IMOD register.
+    pub fn adapt(&mut self, transfer_events: usize) -> bool {
+        let Some((min_imodi, max_imodi)) = self.adaptive else {
+            return false;
+        };
+        if transfer_events == 0 {
+            return false;
+        }
+        let imodi = if transfer_events >= BUSY_TRANSFER_EVENTS {
+            // Go up from 0 too
+            self.imodi.saturating_mul(2).max(1)
+        } else if transfer_events <= IDLE_TRANSFER_EVENTS {
+            self.imodi / 2
+        } else {
+            self.imodi
+        }
+        .clamp(min_imodi, max_imodi);
+        let changed = imodi != self.imodi;
+        self.imodi = imodi;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive() -> Moderation {
+        Moderation {
+            imodi: 100,
+            adaptive: Some((10, 1000)),
+        }
+    }
+
+    #[test]
+    fn busy_doubles() {
+        let mut moderation = adaptive();
+        assert!(!moderation.adapt(BUSY_TRANSFER_EVENTS - 1));
+        assert_eq!(moderation.imodi, 100);
+        assert!(moderation.adapt(BUSY_TRANSFER_EVENTS));
+        assert_eq!(moderation.imodi, 200);
+        // Clamped to the maximum
+        for _ in 0..4 {
+            moderation.adapt(BUSY_TRANSFER_EVENTS);
+        }
+        assert_eq!(moderation.imodi, 1000);
+        assert!(!moderation.adapt(BUSY_TRANSFER_EVENTS));
+    }
+
+    #[test]
+    fn busy_goes_up_from_0() {
+        let mut moderation = Moderation {
+            imodi: 0,
+            adaptive: Some((0, 1000)),
+        };
+        assert!(moderation.adapt(BUSY_TRANSFER_EVENTS));
+        assert_eq!(moderation.imodi, 1);
+    }
+
+    #[test]
+    fn idle_halves() {
+        let mut moderation = adaptive();
+        assert!(!moderation.adapt(IDLE_TRANSFER_EVENTS + 1));
+        assert_eq!(moderation.imodi, 100);
+        assert!(moderation.adapt(IDLE_TRANSFER_EVENTS));
+        assert_eq!(moderation.imodi, 50);
+        // Clamped to the minimum
+        for _ in 0..4 {
+            moderation.adapt(IDLE_TRANSFER_EVENTS);
+        }
+        assert_eq!(moderation.imodi, 10);
+    }
+
+    #[test]
+    fn no_transfer_events_is_ignored() {
+        let mut moderation = adaptive();
+        assert!(!moderation.adapt(0));
+        assert_eq!(moderation.imodi, 100);
+    }
+
+    #[test]
+    fn fixed_never_changes() {
+        let mut moderation = Moderation::fixed(100);
+        assert!(!moderation.adapt(BUSY_TRANSFER_EVENTS));
+        assert!(!moderation.adapt(IDLE_TRANSFER_EVENTS));
+        assert_eq!(moderation.imodi, 100);
+    }
+}
@@ -1,3 +1,5 @@
+use core::num::NonZero;
+
 use bitfield::bitfield;
 use debug_ignore::DebugIgnore;
 use volatile::{
@@ -5,6 +7,8 @@ use volatile::{
     access::{NoAccess, ReadOnly, ReadWrite},
 };
 
+use crate::mem::MIN_PAGE_SIZE;
+
 /// xHCI 5.4 Host Controller Operational Registers
 #[derive(Debug, VolatileFieldAccess, Clone, Copy)]
 #[repr(C)]
@@ -95,6 +99,17 @@ bitfield! {
     pub hce, _: 12;
 }
 
+impl UsbSts {
+    /// HSE, EINT, PCD and SRE are RW1C, and everything else is Read Only
+    const RW1C_MASK: u32 = (1 << 2) | (1 << 3) | (1 << 4) | (1 << 10);
+
+    /// Returns a value that can be written back to USBSTS without clearing anything.
+    /// Set the bits you want to clear after calling this.
+    pub fn to_neutral(self) -> Self {
+        Self(self.0 & !Self::RW1C_MASK)
+    }
+}
+
 bitfield! {
     /// xHCI 5.4.3 Page Size Register (PAGESIZE)
     #[derive(Clone, Copy)]
@@ -104,6 +119,15 @@ bitfield! {
     u16; pub page_size, _: 15, 0;
 }
 
+impl PageSizeReg {
+    /// If bit n is set, the xHC supports a page size of 2^(n+12) bytes.
+    /// Returns the smallest page size that the xHC supports, or `None` if no bits are set.
+    pub fn page_size_bytes(&self) -> Option<NonZero<u64>> {
+        NonZero::new(self.page_size())
+            .and_then(|page_size| NonZero::new(MIN_PAGE_SIZE.get() << page_size.trailing_zeros()))
+    }
+}
+
 bitfield! {
     /// xHCI 5.4.4 Device Notification Control Register (DNCTRL)
     #[derive(Clone, Copy)]
@@ -189,7 +213,7 @@ bitfield! {
     pub csc, set_csc: 17;
     pub pec, set_pec: 18;
     pub wrc, set_wrc: 19;
-    pub occ, set_occ: 29;
+    pub occ, set_occ: 20;
     pub prc, set_prc: 21;
     pub plc, set_plc: 22;
     pub cec, set_cec: 23;
@@ -200,3 +224,19 @@ bitfield! {
     pub device_removable, _: 30;
     pub warm_port_reset, set_warm_port_reset: 31;
 }
+
+impl PortStatusAndControl {
+    /// Bits that are Read Only or Read/Write, so writing back the value we read doesn't change anything.
+    /// Every other bit is either RW1C (writing back a `1` clears it) or RW1S (writing a `1` starts something, like a reset).
+    const PRESERVE_MASK: u32 = {
+        let read_only = (1 << 0) | (1 << 3) | (0xF << 10) | (1 << 30);
+        let read_write = (0xF << 5) | (1 << 9) | (0x7 << 14) | (0x7 << 25);
+        read_only | read_write
+    };
+
+    /// Returns a value that can be written back to PORTSC without changing anything (Linux calls this `xhci_port_state_to_neutral`).
+    /// Set the bits you want to change after calling this.
+    pub fn to_neutral(self) -> Self {
+        Self(self.0 & Self::PRESERVE_MASK)
+    }
+}
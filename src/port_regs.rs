@@ -0,0 +1,22 @@
+use volatile::{VolatileFieldAccess, access::ReadWrite};
+
+use crate::operational_regs::PortStatusAndControl;
+
+/// The port register sets start at offset 0x400 from the operational registers. There is one for each port, up to 255 ports.
+pub const PORT_REGS_OFFSET: usize = 0x400;
+
+pub type PortRegsArray = [PortRegs; 255];
+
+/// xHCI 5.4.8 - 5.4.11 Port Register Set
+#[derive(Debug, VolatileFieldAccess, Clone, Copy)]
+#[repr(C)]
+pub struct PortRegs {
+    #[access(ReadWrite)]
+    pub portsc: PortStatusAndControl,
+    #[access(ReadWrite)]
+    pub portpmsc: u32,
+    #[access(ReadWrite)]
+    pub portli: u32,
+    #[access(ReadWrite)]
+    pub porthlpmc: u32,
+}
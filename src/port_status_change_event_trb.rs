@@ -0,0 +1,47 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.3 Port Status Change Event TRB
+/// Generated when one of the change bits in a port's PORTSC goes from 0 to 1. Read PORTSC to find out what changed.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciPortStatusChangeEventTrb {
+    pub port: PortStatusChangeEventPort,
+    _reserved_0: u32,
+    pub status: PortStatusChangeEventStatus,
+    pub control: PortStatusChangeEventControl,
+}
+
+event_trb!(
+    XhciPortStatusChangeEventTrb,
+    XhciTrbType::PortStatusChangeEvent
+);
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct PortStatusChangeEventPort(u32);
+    impl Debug;
+
+    u8;
+    /// The root hub port number, starting at 1
+    pub port_id, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct PortStatusChangeEventStatus(u32);
+    impl Debug;
+
+    u8; pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct PortStatusChangeEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    u8; pub trb_type, _: 15, 10;
+}
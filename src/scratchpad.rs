@@ -0,0 +1,90 @@
+use alloc::vec::Vec;
+use core::{mem::MaybeUninit, num::NonZero};
+
+use crate::*;
+
+/// xHCI 4.20 Scratchpad Buffers
+/// Memory that the xHC uses for itself. We just have to allocate it and never touch it.
+#[derive(Debug)]
+pub struct ScratchpadBuffers {
+    array_mem: XhciAllocation,
+    buffers: Vec<XhciAllocation>,
+}
+
+impl ScratchpadBuffers {
+    pub fn new(
+        max_scratchpad_buffers: NonZero<u16>,
+        page_size: NonZero<u64>,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, InitError> {
+        // 2. Software allocates a Scratchpad Buffer Array with Max Scratchpad Buffers entries.
+        let scratchpad_array_len = max_scratchpad_buffers.get() as usize;
+        let scratchpad_array_mem = XhciAllocation::new(
+            allocator,
+            AllocRequest {
+                size: NonZero::<u64>::from(max_scratchpad_buffers)
+                    .saturating_mul(const { NonZero::new(size_of::<u64>() as u64).unwrap() }),
+                align: XHCI_SCRATCHPAD_BUFFER_ARRAY_ALIGNMENT,
+                boundary: xhci_scratchpad_buffer_array_boundary(page_size),
+                max_phys_addr,
+            },
+        )?;
+        let mut scratchpad_buffers = Self {
+            array_mem: scratchpad_array_mem,
+            buffers: Vec::with_capacity(scratchpad_array_len),
+        };
+        let scratchpad_array = unsafe {
+            scratchpad_buffers
+                .array_mem
+                .response()
+                .as_uninit_slice::<u64>(scratchpad_array_len)
+        };
+        // 4. For each entry in the Scratchpad Buffer Array:
+        for scratchpad_array_entry in scratchpad_array {
+            // a. Software allocates a PAGESIZE Scratchpad Buffer.
+            let scratchpad_buffer_size = xhci_scratchpad_buffers_max_size(page_size);
+            let scratchpad_buffer_mem = match XhciAllocation::new(
+                allocator,
+                AllocRequest {
+                    size: scratchpad_buffer_size,
+                    align: xhci_scratchpad_buffers_alignment(page_size),
+                    boundary: xhci_scratchpad_buffers_boundary(page_size),
+                    max_phys_addr,
+                },
+            ) {
+                Ok(scratchpad_buffer_mem) => scratchpad_buffer_mem,
+                Err(e) => {
+                    // The xHC doesn't know about any of this memory yet, so we can give it back
+                    unsafe { scratchpad_buffers.free(allocator) };
+                    return Err(e.into());
+                }
+            };
+            let scratchpad_buffer = unsafe {
+                scratchpad_buffer_mem
+                    .response()
+                    .as_uninit_slice::<u8>(scratchpad_buffer_size.get() as usize)
+            };
+            // b. Software clears the Scratchpad Buffer to ‘0’.
+            scratchpad_buffer.fill(MaybeUninit::zeroed());
+            // c. Software writes the base address of the allocated Scratchpad Buffer to associated entry in the Scratchpad Buffer Array.
+            scratchpad_array_entry.write(scratchpad_buffer_mem.phys_addr());
+            scratchpad_buffers.buffers.push(scratchpad_buffer_mem);
+        }
+        Ok(scratchpad_buffers)
+    }
+
+    /// The address of the Scratchpad Buffer Array, which goes in entry 0 of the DCBAA
+    pub fn phys_addr(&self) -> u64 {
+        self.array_mem.phys_addr()
+    }
+
+    /// # Safety
+    /// The xHC must be halted, or the DCBAA must no longer point to these buffers.
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        for buffer in self.buffers.into_iter().rev() {
+            unsafe { buffer.free(allocator) };
+        }
+        unsafe { self.array_mem.free(allocator) };
+    }
+}
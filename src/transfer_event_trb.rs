@@ -0,0 +1,55 @@
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+/// xHCI 6.4.2.1 Transfer Event TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct XhciTransferEventTrb {
+    /// The physical address of the TRB that generated this event.
+    /// If [`TransferEventControl::event_data`] is set, this is the Event Data from an Event Data TRB instead.
+    pub trb_pointer: u64,
+    pub status: TransferEventStatus,
+    pub control: TransferEventControl,
+}
+
+event_trb!(XhciTransferEventTrb, XhciTrbType::TransferEvent);
+
+impl XhciTransferEventTrb {
+    /// `Ok` if the transfer succeeded. A Short Packet counts as success, since the device is allowed to send less than we asked for.
+    /// Use [`TransferEventStatus::trb_transfer_length`] to find out how much was transferred.
+    pub fn result(&self) -> Result<(), CompletionError> {
+        match self.status.completion_code() {
+            CompletionCode::ShortPacket => Ok(()),
+            completion_code => completion_code.into_result(),
+        }
+    }
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct TransferEventStatus(u32);
+    impl Debug;
+
+    u32;
+    /// The number of bytes that were not transferred (the residue), not the number that were
+    pub trb_transfer_length, _: 23, 0;
+    u8;
+    /// Refer to section 6.4.5 for an enumerated list of possible error conditions.
+    pub into CompletionCode, completion_code, _: 31, 24;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct TransferEventControl(u32);
+    impl Debug;
+
+    pub cycle_bit, _: 0;
+    pub event_data, _: 2;
+    u8; pub trb_type, _: 15, 10;
+    u8;
+    /// The Device Context Index of the endpoint
+    pub endpoint_id, _: 20, 16;
+    u8; pub slot_id, _: 31, 24;
+}
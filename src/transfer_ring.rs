@@ -0,0 +1,387 @@
+use alloc::collections::vec_deque::VecDeque;
+use core::{
+    num::NonZero,
+    sync::atomic::{Ordering, fence},
+};
+
+use zerocopy::{FromZeros, transmute};
+
+use crate::*;
+
+/// xHCI 4.9.2 Transfer Ring Management
+/// The transfer ring of one endpoint. The driver owns it, so that Transfer Events from [`Driver::handle_interrupt`] can complete the transfers on it.
+///
+/// Every TRB that we enqueue is its own TD (the Chain bit is never set), so the Link TRB never needs the Chain bit either.
+#[derive(Debug)]
+pub struct TransferRing<'a> {
+    ring_mem: XhciAllocation,
+    ring: &'a mut [AnyTrb],
+    enqueue_pointer: usize,
+    producer_cycle_state: bool,
+    /// Only updated when a Transfer Event tells us that the xHC finished a transfer
+    dequeue_pointer: usize,
+    consumer_cycle_state: bool,
+    /// Transfers that didn't get their Transfer Event yet, oldest first
+    pending: VecDeque<PendingTransfer>,
+    /// The Interrupter Target of every TRB on the ring, which is where their Transfer Events go
+    interrupter_target: u16,
+}
+
+/// The TRBs of one transfer (for example the Setup, Data and Status Stages of a control transfer)
+#[derive(Debug)]
+struct PendingTransfer {
+    first_trb: usize,
+    last_trb: usize,
+    completer: Completer<XhciTransferEventTrb>,
+}
+
+impl PendingTransfer {
+    fn contains(&self, index: usize) -> bool {
+        if self.first_trb <= self.last_trb {
+            (self.first_trb..=self.last_trb).contains(&index)
+        } else {
+            // The transfer wrapped around the Link TRB
+            index >= self.first_trb || index <= self.last_trb
+        }
+    }
+}
+
+impl TransferRing<'_> {
+    pub fn new(
+        len: usize,
+        interrupter_target: u16,
+        max_phys_addr: u64,
+        allocator: &mut impl XhciMemAllocator,
+    ) -> Result<Self, AllocError> {
+        let ring_mem = XhciAllocation::new(
+            allocator,
+            AllocRequest {
+                size: NonZero::new((len * size_of::<AnyTrb>()) as u64).ok_or(AllocError)?,
+                align: XHCI_TRANSFER_RING_SEGMENTS_ALIGNMENT,
+                boundary: XHCI_TRANSFER_RING_SEGMENTS_BOUNDARY,
+                max_phys_addr,
+            },
+        )?;
+        let ring = unsafe { ring_mem.response().as_zeroed_slice::<AnyTrb>(len) };
+        let mut transfer_ring = Self {
+            ring_mem,
+            ring,
+            enqueue_pointer: 0,
+            producer_cycle_state: true,
+            dequeue_pointer: 0,
+            consumer_cycle_state: true,
+            pending: VecDeque::new(),
+            interrupter_target,
+        };
+        transfer_ring.reinitialize();
+        Ok(transfer_ring)
+    }
+
+    /// Puts the ring back into the state it was in right after it was allocated.
+    /// Transfers that were on it are cancelled.
+    /// The xHC must not be using the ring (the endpoint is stopped or disabled, or the xHC is halted).
+    pub fn reinitialize(&mut self) {
+        self.ring.zero();
+        let link_trb_index = self.ring.len() - 1;
+        self.ring[link_trb_index] = transmute!(LinkTrb::new(self.ring_mem.phys_addr(), true, true));
+        self.enqueue_pointer = 0;
+        self.producer_cycle_state = true;
+        self.dequeue_pointer = 0;
+        self.consumer_cycle_state = true;
+        self.pending.clear();
+    }
+
+    /// # Safety
+    /// The xHC must not be using the ring (the endpoint is disabled, or the xHC is halted).
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe { self.ring_mem.free(allocator) };
+    }
+
+    fn trb_phys_addr(&self, index: usize) -> u64 {
+        self.ring_mem.phys_addr() + index as u64 * size_of::<AnyTrb>() as u64
+    }
+
+    /// The value for the TR Dequeue Pointer field of the Endpoint Context (or the Set TR Dequeue Pointer Command),
+    /// which points to the first TRB that the xHC hasn't finished.
+    pub fn tr_dequeue_pointer(&self) -> TrDequeuePointer {
+        let mut tr_dequeue_pointer = TrDequeuePointer(0);
+        tr_dequeue_pointer.set_tr_dequeue_pointer(self.trb_phys_addr(self.dequeue_pointer));
+        tr_dequeue_pointer.set_dcs(self.consumer_cycle_state);
+        tr_dequeue_pointer
+    }
+
+    /// The number of TRBs that can be enqueued before the ring is full
+    fn free_len(&self) -> usize {
+        if self.consumer_cycle_state == self.producer_cycle_state {
+            self.ring.len() - 1 - (self.enqueue_pointer - self.dequeue_pointer)
+        } else {
+            self.dequeue_pointer - self.enqueue_pointer
+        }
+    }
+
+    /// Enqueues the TRBs of one transfer. `completer` is completed with the Transfer Event of the last TRB, or of the TRB that failed.
+    /// The cycle bits will be set by this function.
+    pub fn try_enqueue(
+        &mut self,
+        trbs: &[AnyTrb],
+        completer: Completer<XhciTransferEventTrb>,
+    ) -> Result<(), EnqueueError> {
+        if trbs.is_empty() || trbs.len() > self.free_len() {
+            return Err(EnqueueError::IsFull);
+        }
+        let first_trb = self.enqueue_pointer;
+        let first_trb_cycle_bit = self.producer_cycle_state;
+        let mut last_trb = first_trb;
+        for (i, trb) in trbs.iter().enumerate() {
+            let mut trb = *trb;
+            // The xHC could still be working on the ring, so the first TRB keeps the wrong cycle bit until the whole transfer is written
+            let cycle_bit = if i == 0 {
+                !self.producer_cycle_state
+            } else {
+                self.producer_cycle_state
+            };
+            trb.control.set_cycle_bit(cycle_bit);
+            // Every transfer TRB has the Interrupter Target in the same place
+            let mut status: TransferTrbStatus = transmute!(trb.status);
+            status.set_interrupter_target(self.interrupter_target);
+            trb.status = transmute!(status);
+            self.ring[self.enqueue_pointer] = trb;
+            last_trb = self.enqueue_pointer;
+
+            self.enqueue_pointer += 1;
+            if self.enqueue_pointer == self.ring.len() - 1 {
+                self.ring[self.enqueue_pointer]
+                    .control
+                    .set_cycle_bit(self.producer_cycle_state);
+                self.producer_cycle_state = !self.producer_cycle_state;
+                self.enqueue_pointer = 0;
+            }
+        }
+        fence(Ordering::Release);
+        self.ring[first_trb]
+            .control
+            .set_cycle_bit(first_trb_cycle_bit);
+        self.pending.push_back(PendingTransfer {
+            first_trb,
+            last_trb,
+            completer,
+        });
+        Ok(())
+    }
+
+    /// Completes the transfer that the Transfer Event is for, and frees up its TRBs.
+    /// A transfer that was stopped by a Stop Endpoint Command stays pending, because the xHC continues it when the doorbell is rung again.
+    pub fn process_event(&mut self, event: &XhciTransferEventTrb) {
+        // We never enqueue Event Data TRBs, so the TRB pointer is always a TRB on this ring
+        let trb_pointer = event.trb_pointer;
+        let index =
+            trb_pointer.wrapping_sub(self.ring_mem.phys_addr()) as usize / size_of::<AnyTrb>();
+        if event.control.event_data() || index >= self.ring.len() - 1 {
+            log::warn!(
+                "xHCI - Got a Transfer Event for {trb_pointer:#X}, which is not on the transfer ring"
+            );
+            return;
+        }
+        // xHCI 4.6.9 Stop Endpoint: the xHC's TR Dequeue Pointer stays on the stopped TRB, so the buffer is still in use.
+        // The transfer is only done when it completes after a restart, or when it's cancelled with `skip_pending`.
+        if matches!(
+            event.status.completion_code(),
+            CompletionCode::Stopped
+                | CompletionCode::StoppedLengthInvalid
+                | CompletionCode::StoppedShortPacket
+        ) {
+            log::debug!("xHCI - Transfer ring stopped at {trb_pointer:#X}");
+            return;
+        }
+        let Some(position) = self
+            .pending
+            .iter()
+            .position(|transfer| transfer.contains(index))
+        else {
+            log::warn!(
+                "xHCI - Got a Transfer Event for {trb_pointer:#X}, which is not a transfer we enqueued"
+            );
+            return;
+        };
+        // Transfers on an endpoint finish in order, so the ones before this one won't get an event anymore
+        for _ in self.pending.drain(..position) {
+            log::warn!("xHCI - A transfer before {trb_pointer:#X} never got a Transfer Event");
+        }
+        let transfer = self.pending.pop_front().unwrap();
+
+        // If the transfer failed in the middle, the endpoint is halted, and software has to move the xHC's dequeue pointer past the transfer anyways
+        let mut new_dequeue_pointer = transfer.last_trb + 1;
+        if new_dequeue_pointer == self.ring.len() - 1 {
+            new_dequeue_pointer = 0;
+        }
+        // If the consumer (xHC) looped around, it must have toggled its consumer cycle state
+        if new_dequeue_pointer <= self.dequeue_pointer {
+            self.consumer_cycle_state = !self.consumer_cycle_state;
+        }
+        self.dequeue_pointer = new_dequeue_pointer;
+        transfer.completer.complete(*event);
+    }
+
+    /// Cancels every pending transfer and treats the TRBs as consumed.
+    /// Returns where the xHC should continue (for a Set TR Dequeue Pointer Command).
+    pub fn skip_pending(&mut self) -> TrDequeuePointer {
+        self.pending.clear();
+        self.dequeue_pointer = self.enqueue_pointer;
+        self.consumer_cycle_state = self.producer_cycle_state;
+        self.tr_dequeue_pointer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use super::*;
+
+    fn transfer_ring(len: usize) -> TransferRing<'static> {
+        TransferRing::new(len, 0, u64::MAX, &mut TestAllocator).unwrap()
+    }
+
+    fn enqueue(
+        transfer_ring: &mut TransferRing,
+        trbs: usize,
+    ) -> Result<TransferFuture, EnqueueError> {
+        let (completer, future) = completion();
+        let trb = NormalTrb::new(TransferBuffer {
+            phys_addr: 0,
+            len: 0,
+        })
+        .into();
+        transfer_ring.try_enqueue(&vec![trb; trbs], completer)?;
+        Ok(future)
+    }
+
+    /// What the xHC would write to the event ring after getting to the TRB at `index`
+    fn transfer_event(
+        transfer_ring: &TransferRing,
+        index: usize,
+        completion_code: CompletionCode,
+    ) -> XhciTransferEventTrb {
+        let mut event = AnyTrb {
+            parameter: transfer_ring.trb_phys_addr(index),
+            status: u32::from(u8::from(completion_code)) << 24,
+            control: AnyTrbControl(0),
+        };
+        event
+            .control
+            .set_trb_type(XhciTrbType::TransferEvent.into());
+        transmute!(event)
+    }
+
+    fn is_complete(future: &mut TransferFuture) -> bool {
+        let mut context = Context::from_waker(Waker::noop());
+        match Pin::new(future).poll(&mut context) {
+            Poll::Ready(result) => {
+                assert!(result.unwrap().result().is_ok());
+                true
+            }
+            Poll::Pending => false,
+        }
+    }
+
+    #[test]
+    fn stopped_transfer_stays_pending() {
+        let mut transfer_ring = transfer_ring(4);
+        let mut future = enqueue(&mut transfer_ring, 1).unwrap();
+        for completion_code in [
+            CompletionCode::Stopped,
+            CompletionCode::StoppedLengthInvalid,
+            CompletionCode::StoppedShortPacket,
+        ] {
+            transfer_ring.process_event(&transfer_event(&transfer_ring, 0, completion_code));
+            assert!(!is_complete(&mut future));
+            assert_eq!(transfer_ring.pending.len(), 1);
+            assert_eq!(transfer_ring.dequeue_pointer, 0);
+        }
+        // The xHC finishes the transfer after the doorbell is rung again
+        transfer_ring.process_event(&transfer_event(&transfer_ring, 0, CompletionCode::Success));
+        assert!(is_complete(&mut future));
+        assert_eq!(transfer_ring.dequeue_pointer, 1);
+        unsafe { transfer_ring.free(&mut TestAllocator) };
+    }
+
+    fn cycle_bits(
+        transfer_ring: &TransferRing,
+        indexes: impl IntoIterator<Item = usize>,
+    ) -> Vec<bool> {
+        indexes
+            .into_iter()
+            .map(|index| transfer_ring.ring[index].control.cycle_bit())
+            .collect()
+    }
+
+    #[test]
+    fn one_trb_transfers_wrap() {
+        let mut transfer_ring = transfer_ring(2);
+        for lap in 0..4 {
+            let cycle_bit = lap % 2 == 0;
+            let mut future = enqueue(&mut transfer_ring, 1).unwrap();
+            assert!(enqueue(&mut transfer_ring, 1).is_err());
+            // The TRB and the Link TRB belong to the xHC now
+            assert_eq!(cycle_bits(&transfer_ring, 0..2), [cycle_bit; 2]);
+            transfer_ring.process_event(&transfer_event(
+                &transfer_ring,
+                0,
+                CompletionCode::Success,
+            ));
+            assert!(is_complete(&mut future));
+            assert_eq!(transfer_ring.free_len(), 1);
+        }
+        unsafe { transfer_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn control_transfers_wrap() {
+        let mut transfer_ring = transfer_ring(4);
+        for lap in 0..4 {
+            let cycle_bit = lap % 2 == 0;
+            let mut future = enqueue(&mut transfer_ring, 3).unwrap();
+            assert!(enqueue(&mut transfer_ring, 1).is_err());
+            assert_eq!(cycle_bits(&transfer_ring, 0..4), [cycle_bit; 4]);
+            transfer_ring.process_event(&transfer_event(
+                &transfer_ring,
+                2,
+                CompletionCode::Success,
+            ));
+            assert!(is_complete(&mut future));
+            assert_eq!(transfer_ring.free_len(), 3);
+        }
+        unsafe { transfer_ring.free(&mut TestAllocator) };
+    }
+
+    #[test]
+    fn transfer_wraps_around_link_trb() {
+        let mut transfer_ring = transfer_ring(5);
+        for index in 0..2 {
+            let mut future = enqueue(&mut transfer_ring, 1).unwrap();
+            transfer_ring.process_event(&transfer_event(
+                &transfer_ring,
+                index,
+                CompletionCode::Success,
+            ));
+            assert!(is_complete(&mut future));
+        }
+        // The Setup and Data Stages are at the end of the ring, and the Status Stage is after the Link TRB
+        let mut future = enqueue(&mut transfer_ring, 3).unwrap();
+        assert_eq!(
+            cycle_bits(&transfer_ring, [2, 3, 4, 0]),
+            [true, true, true, false]
+        );
+        assert_eq!(transfer_ring.free_len(), 1);
+        transfer_ring.process_event(&transfer_event(&transfer_ring, 0, CompletionCode::Success));
+        assert!(is_complete(&mut future));
+        assert_eq!(transfer_ring.free_len(), 4);
+        assert!(!transfer_ring.tr_dequeue_pointer().dcs());
+        unsafe { transfer_ring.free(&mut TestAllocator) };
+    }
+}
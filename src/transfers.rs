@@ -0,0 +1,280 @@
+// xHCI 6.4.1 Transfer TRBs
+// Like the command TRBs, every transfer TRB has its own struct with the same layout as the TRB.
+// The Cycle bit is set by the transfer ring when the TD is enqueued.
+use bitfield::bitfield;
+use zerocopy::{FromBytes, Immutable, IntoBytes, transmute};
+
+use crate::*;
+
+macro_rules! transfer_trb {
+    ($trb:ty) => {
+        impl From<$trb> for AnyTrb {
+            fn from(value: $trb) -> Self {
+                transmute!(value)
+            }
+        }
+    };
+}
+
+/// xHCI 9.3 USB Device Requests (the 8 bytes sent in the Setup Stage)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    /// bmRequestType. Bit 7 is the direction of the Data Stage (1 = device to host).
+    pub request_type: u8,
+    /// bRequest
+    pub request: u8,
+    /// wValue
+    pub value: u16,
+    /// wIndex
+    pub index: u16,
+    /// wLength, which must match the length of the data buffer
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Whether the Data Stage goes from the device to the host
+    pub fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+}
+
+/// A buffer that the xHC reads from or writes to during a transfer.
+/// It can't cross a 64 KiB boundary (xHCI 6.4.1: "The data buffer referenced by a [Normal / Data Stage] TRB shall not span a 64KB boundary"),
+/// so the driver can always describe it with a single TRB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferBuffer {
+    pub phys_addr: u64,
+    pub len: u32,
+}
+
+/// xHCI 6.4.1: a TRB's data buffer can't be bigger than 64 KiB or cross a 64 KiB boundary
+const TRB_BUFFER_BOUNDARY: u64 = 1024 * 64;
+
+impl TransferBuffer {
+    /// Also checks that the xHC can access the whole buffer, since it would silently ignore any address bits it doesn't support
+    pub(crate) fn fits_in_trb(&self, max_phys_addr: u64) -> bool {
+        match self.len.checked_sub(1) {
+            Some(last_offset) => {
+                self.phys_addr
+                    .checked_add(last_offset as u64)
+                    .is_some_and(|last_byte| {
+                        self.phys_addr / TRB_BUFFER_BOUNDARY == last_byte / TRB_BUFFER_BOUNDARY
+                            && last_byte <= max_phys_addr
+                    })
+            }
+            // A zero-length packet doesn't access the buffer
+            None => true,
+        }
+    }
+}
+
+bitfield! {
+    /// The status field of TRBs that point to a data buffer
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct TransferTrbStatus(u32);
+    impl Debug;
+
+    u32; pub trb_transfer_length, set_trb_transfer_length: 16, 0;
+    u8;
+    /// The number of packets that are left in the TD after this TRB. Always 0 for the last TRB of a TD.
+    pub td_size, set_td_size: 21, 17;
+    u16; pub interrupter_target, set_interrupter_target: 31, 22;
+}
+
+impl TransferTrbStatus {
+    fn new(len: u32) -> Self {
+        let mut status = Self(0);
+        status.set_trb_transfer_length(len);
+        status
+    }
+}
+
+/// xHCI 6.4.1.1 Normal TRB
+/// Used for bulk and interrupt transfers.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct NormalTrb {
+    pub data_buffer_pointer: u64,
+    pub status: TransferTrbStatus,
+    pub control: NormalTrbControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct NormalTrbControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    pub ent, set_ent: 1;
+    /// Interrupt-on Short Packet
+    pub isp, set_isp: 2;
+    pub ns, set_ns: 3;
+    pub chain, set_chain: 4;
+    /// Interrupt On Completion
+    pub ioc, set_ioc: 5;
+    /// Immediate Data
+    pub idt, set_idt: 6;
+    pub bei, set_bei: 9;
+    u8; pub trb_type, set_trb_type: 15, 10;
+}
+
+impl NormalTrb {
+    /// The xHC generates a Transfer Event when it's done (IOC is set)
+    pub fn new(buffer: TransferBuffer) -> Self {
+        let mut control = NormalTrbControl(0);
+        control.set_trb_type(XhciTrbType::Normal.into());
+        control.set_ioc(true);
+        Self {
+            data_buffer_pointer: buffer.phys_addr,
+            status: TransferTrbStatus::new(buffer.len),
+            control,
+        }
+    }
+}
+
+transfer_trb!(NormalTrb);
+
+/// xHCI 6.4.1.2.1 Setup Stage TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct SetupStageTrb {
+    pub setup: SetupStageParameter,
+    pub status: TransferTrbStatus,
+    pub control: SetupStageTrbControl,
+}
+
+bitfield! {
+    /// The Setup Packet, which is sent as Immediate Data
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetupStageParameter(u64);
+    impl Debug;
+
+    u8; pub request_type, set_request_type: 7, 0;
+    u8; pub request, set_request: 15, 8;
+    u16; pub value, set_value: 31, 16;
+    u16; pub index, set_index: 47, 32;
+    u16; pub length, set_length: 63, 48;
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct SetupStageTrbControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    pub ioc, set_ioc: 5;
+    pub idt, set_idt: 6;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    u8;
+    /// Transfer Type: 0 = No Data Stage, 2 = OUT Data Stage, 3 = IN Data Stage
+    pub trt, set_trt: 17, 16;
+}
+
+impl SetupStageTrb {
+    pub fn new(setup: SetupPacket, has_data_stage: bool) -> Self {
+        let mut parameter = SetupStageParameter(0);
+        parameter.set_request_type(setup.request_type);
+        parameter.set_request(setup.request);
+        parameter.set_value(setup.value);
+        parameter.set_index(setup.index);
+        parameter.set_length(setup.length);
+        let mut control = SetupStageTrbControl(0);
+        control.set_trb_type(XhciTrbType::SetupStage.into());
+        // The Setup Packet is always 8 bytes of Immediate Data
+        control.set_idt(true);
+        control.set_trt(match (has_data_stage, setup.is_in()) {
+            (false, _) => 0,
+            (true, false) => 2,
+            (true, true) => 3,
+        });
+        Self {
+            setup: parameter,
+            status: TransferTrbStatus::new(8),
+            control,
+        }
+    }
+}
+
+transfer_trb!(SetupStageTrb);
+
+/// xHCI 6.4.1.2.2 Data Stage TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct DataStageTrb {
+    pub data_buffer_pointer: u64,
+    pub status: TransferTrbStatus,
+    pub control: DataStageTrbControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct DataStageTrbControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    pub ent, set_ent: 1;
+    pub isp, set_isp: 2;
+    pub ns, set_ns: 3;
+    pub chain, set_chain: 4;
+    pub ioc, set_ioc: 5;
+    pub idt, set_idt: 6;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    /// 1 = IN (device to host)
+    pub dir, set_dir: 16;
+}
+
+impl DataStageTrb {
+    pub fn new(buffer: TransferBuffer, is_in: bool) -> Self {
+        let mut control = DataStageTrbControl(0);
+        control.set_trb_type(XhciTrbType::DataStage.into());
+        control.set_dir(is_in);
+        Self {
+            data_buffer_pointer: buffer.phys_addr,
+            status: TransferTrbStatus::new(buffer.len),
+            control,
+        }
+    }
+}
+
+transfer_trb!(DataStageTrb);
+
+/// xHCI 6.4.1.2.3 Status Stage TRB
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C)]
+pub struct StatusStageTrb {
+    _reserved_0: u64,
+    pub status: TransferTrbStatus,
+    pub control: StatusStageTrbControl,
+}
+
+bitfield! {
+    #[derive(Clone, Copy, FromBytes, IntoBytes, Immutable)]
+    pub struct StatusStageTrbControl(u32);
+    impl Debug;
+
+    pub cycle_bit, set_cycle_bit: 0;
+    pub ent, set_ent: 1;
+    pub chain, set_chain: 4;
+    pub ioc, set_ioc: 5;
+    u8; pub trb_type, set_trb_type: 15, 10;
+    /// 1 = IN (device to host)
+    pub dir, set_dir: 16;
+}
+
+impl StatusStageTrb {
+    /// xHCI 4.11.2.2: the Status Stage goes in the opposite direction of the Data Stage, or IN if there is no Data Stage.
+    /// The xHC generates a Transfer Event when it's done (IOC is set), which ends the control transfer.
+    pub fn new(setup: SetupPacket, has_data_stage: bool) -> Self {
+        let mut control = StatusStageTrbControl(0);
+        control.set_trb_type(XhciTrbType::StatusStage.into());
+        control.set_dir(!(has_data_stage && setup.is_in()));
+        control.set_ioc(true);
+        Self {
+            _reserved_0: 0,
+            status: TransferTrbStatus(0),
+            control,
+        }
+    }
+}
+
+transfer_trb!(StatusStageTrb);
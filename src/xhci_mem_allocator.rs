@@ -1,10 +1,31 @@
 // This module is just for having a Rust library. This alloc request and response is not actually part of the xHCI spec.
-use core::num::NonZero;
+use core::{mem::MaybeUninit, num::NonZero, slice};
+
+use zerocopy::FromZeros;
 
 /// # Safety
 /// You must allocate physical memory and make sure it is mapped as Strong Uncacheable (UC).
 pub unsafe trait XhciMemAllocator {
-    fn alloc(&mut self, request: AllocRequest) -> AllocResponse;
+    /// Return an [`AllocError`] if the request cannot be satisfied.
+    /// The driver will report it as [`InitError::AllocFailed`](crate::InitError::AllocFailed) instead of panicking.
+    fn alloc(&mut self, request: AllocRequest) -> Result<AllocResponse, AllocError>;
+
+    /// Give back memory from [`Self::alloc`].
+    ///
+    /// # Safety
+    /// `response` must have been returned by calling [`Self::alloc`] with `request`, and must not have been freed already.
+    /// The driver makes sure the xHC and the driver will never access the memory again.
+    unsafe fn free(&mut self, response: AllocResponse, request: AllocRequest);
+}
+
+unsafe impl<A: XhciMemAllocator + ?Sized> XhciMemAllocator for &mut A {
+    fn alloc(&mut self, request: AllocRequest) -> Result<AllocResponse, AllocError> {
+        (**self).alloc(request)
+    }
+
+    unsafe fn free(&mut self, response: AllocResponse, request: AllocRequest) {
+        unsafe { (**self).free(response, request) }
+    }
 }
 
 /// Requirements for kernel-specific allocation.
@@ -19,6 +40,9 @@ pub struct AllocRequest {
     pub align: NonZero<u64>,
     /// Must be a power of 2.
     pub boundary: NonZero<u64>,
+    /// The whole allocation has to be at or below this physical address.
+    /// This is `u32::MAX` for xHCs that can't do 64-bit addressing (HCCPARAMS1.AC64 = 0), and `u64::MAX` otherwise.
+    pub max_phys_addr: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -26,3 +50,112 @@ pub struct AllocResponse {
     pub phys_addr: u64,
     pub virt_addr: NonZero<usize>,
 }
+
+impl AllocResponse {
+    /// Checks the physical address against the alignment, boundary and max physical address in `request`
+    pub fn satisfies(&self, request: &AllocRequest) -> bool {
+        let Some(last_byte) = self.phys_addr.checked_add(request.size.get() - 1) else {
+            return false;
+        };
+        let is_aligned = self.phys_addr % request.align == 0;
+        let crosses_boundary = self.phys_addr / request.boundary != last_byte / request.boundary;
+        is_aligned && !crosses_boundary && last_byte <= request.max_phys_addr
+    }
+
+    /// # Safety
+    /// The allocation must be big enough for `len` `T`s, aligned for `T`, and not be referenced anywhere else.
+    pub(crate) unsafe fn as_uninit_slice<'a, T>(&self, len: usize) -> &'a mut [MaybeUninit<T>] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr.get() as *mut MaybeUninit<T>, len) }
+    }
+
+    /// Fills the allocation with `0`s and returns it as a slice of `len` `T`s.
+    ///
+    /// # Safety
+    /// Same as [`Self::as_uninit_slice`]
+    pub(crate) unsafe fn as_zeroed_slice<'a, T: FromZeros>(&self, len: usize) -> &'a mut [T] {
+        let ptr = self.virt_addr.get() as *mut T;
+        unsafe { ptr.write_bytes(0, len) };
+        // Safety: all zeroes is a valid `T`
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    }
+}
+
+/// Memory that the driver allocated, along with the request, so that it can be freed.
+/// This is intentionally not `Clone`, so that whatever owns it is responsible for freeing it exactly once.
+#[derive(Debug)]
+pub(crate) struct XhciAllocation {
+    request: AllocRequest,
+    response: AllocResponse,
+}
+
+impl XhciAllocation {
+    pub fn new(
+        allocator: &mut impl XhciMemAllocator,
+        request: AllocRequest,
+    ) -> Result<Self, AllocError> {
+        let response = allocator.alloc(request)?;
+        // We can't trust the allocator, and the xHC would silently ignore any address bits it doesn't support,
+        // so it would end up accessing the wrong memory.
+        if !response.satisfies(&request) {
+            log::error!(
+                "xHCI - Allocator returned physical address {:#X}, which doesn't satisfy {request:X?}",
+                response.phys_addr
+            );
+            unsafe { allocator.free(response, request) };
+            return Err(AllocError);
+        }
+        Ok(Self { request, response })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.response.phys_addr
+    }
+
+    pub fn response(&self) -> &AllocResponse {
+        &self.response
+    }
+
+    /// # Safety
+    /// Neither the xHC nor the driver can access the memory after this.
+    pub unsafe fn free(self, allocator: &mut impl XhciMemAllocator) {
+        unsafe { allocator.free(self.response, self.request) };
+    }
+}
+
+/// The allocator ran out of memory or could not meet the alignment / boundary / address requirements
+#[derive(Debug, Clone, Copy)]
+pub struct AllocError;
+
+/// Heap memory, where the physical address is the virtual address
+#[cfg(test)]
+pub(crate) struct TestAllocator;
+
+#[cfg(test)]
+impl TestAllocator {
+    /// Aligning to the boundary makes sure the allocation doesn't cross it
+    fn layout(request: AllocRequest) -> alloc::alloc::Layout {
+        alloc::alloc::Layout::from_size_align(
+            request.size.get() as usize,
+            request.align.max(request.boundary).get() as usize,
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+unsafe impl XhciMemAllocator for TestAllocator {
+    fn alloc(&mut self, request: AllocRequest) -> Result<AllocResponse, AllocError> {
+        let ptr = unsafe { alloc::alloc::alloc_zeroed(Self::layout(request)) };
+        let virt_addr = NonZero::new(ptr as usize).ok_or(AllocError)?;
+        Ok(AllocResponse {
+            phys_addr: virt_addr.get() as u64,
+            virt_addr,
+        })
+    }
+
+    unsafe fn free(&mut self, response: AllocResponse, request: AllocRequest) {
+        unsafe {
+            alloc::alloc::dealloc(response.virt_addr.get() as *mut u8, Self::layout(request))
+        };
+    }
+}